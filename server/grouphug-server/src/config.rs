@@ -3,11 +3,22 @@ use serde::Deserialize;
 #[derive(Deserialize)]
 pub struct Config {
     pub electrum: Electrum,
+    pub bitcoind: Option<Bitcoind>,
     pub group: GroupConf,
+    #[serde(default)]
+    pub close: CloseConf,
     pub dust: Dust,
     pub fee: Fee,
     pub server: Server,
     pub network: Network,
+    #[serde(default)]
+    pub tracker: TrackerConf,
+    #[serde(default)]
+    pub quota: QuotaConf,
+    #[serde(default)]
+    pub denomination: DenominationConf,
+    #[serde(default)]
+    pub channel: Vec<Channel>,
 }
 
 #[derive(Deserialize)]
@@ -16,10 +27,142 @@ pub struct Electrum {
     pub certificate_validation: bool,
 }
 
+// Extra Electrum server the group transactions are broadcasted through
+#[derive(Deserialize)]
+pub struct Channel {
+    pub endpoint: String,
+    #[serde(default = "default_certificate_validation")]
+    pub certificate_validation: bool,
+    pub socks5: Option<String>,
+}
+
+fn default_certificate_validation() -> bool { true }
+
+#[derive(Deserialize)]
+pub struct Bitcoind {
+    pub url: String,
+    pub user: String,
+    pub password: String,
+}
+
 #[derive(Deserialize)]
 pub struct GroupConf {
     pub max_time: i64,
     pub max_size: usize,
+    #[serde(default = "default_max_per_band")]
+    pub max_per_band: usize,
+    #[serde(default)]
+    pub assignment: Assignment,
+    #[serde(default)]
+    pub truc: bool,
+    #[serde(default)]
+    pub homogeneous_scripts: bool,
+    #[serde(default)]
+    pub anchor: bool,
+    #[serde(default = "default_anchor_value")]
+    pub anchor_value: u64,
+}
+
+fn default_max_per_band() -> usize { 1 }
+fn default_anchor_value() -> u64 { 240 }
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Assignment {
+    #[default]
+    Oldest,
+    RoundRobin,
+}
+
+#[derive(Deserialize)]
+pub struct CloseConf {
+    pub max_vbytes: Option<usize>,
+    pub max_fee: Option<u64>,
+    pub fee_margin: Option<f32>,
+    #[serde(default)]
+    pub new_block: bool,
+    #[serde(default = "default_min_submitters")]
+    pub min_submitters: usize,
+    #[serde(default = "default_min_connections")]
+    pub min_connections: usize,
+    #[serde(default)]
+    pub below_minimum: BelowMinimum,
+    #[serde(default = "default_release_after")]
+    pub release_after: i64,
+    pub merge_below: Option<usize>,
+    #[serde(default = "default_target")]
+    pub target: usize,
+    #[serde(default = "default_estimate_ttl")]
+    pub estimate_ttl: i64,
+    #[serde(default = "default_stale_after")]
+    pub stale_after: i64,
+    #[serde(default)]
+    pub broadcast_delay: u64,
+    #[serde(default)]
+    pub tick_jitter: u64,
+}
+
+impl Default for CloseConf {
+    fn default() -> Self {
+        CloseConf {
+            max_vbytes: None,
+            max_fee: None,
+            fee_margin: None,
+            new_block: false,
+            min_submitters: default_min_submitters(),
+            min_connections: default_min_connections(),
+            below_minimum: BelowMinimum::default(),
+            release_after: default_release_after(),
+            merge_below: None,
+            target: default_target(),
+            estimate_ttl: default_estimate_ttl(),
+            stale_after: default_stale_after(),
+            broadcast_delay: 0,
+            tick_jitter: 0,
+        }
+    }
+}
+
+fn default_min_submitters() -> usize { 1 }
+fn default_min_connections() -> usize { 1 }
+fn default_release_after() -> i64 { 3600 }
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BelowMinimum {
+    #[default]
+    Wait,
+    Merge,
+    Release,
+}
+fn default_target() -> usize { 1 }
+fn default_estimate_ttl() -> i64 { 300 }
+fn default_stale_after() -> i64 { 600 }
+
+// Maximum number of pairs of a group coming from the same source
+#[derive(Deserialize, Default)]
+pub struct QuotaConf {
+    pub per_submission: Option<usize>,
+    pub per_connection: Option<usize>,
+    pub per_ip: Option<usize>,
+    pub per_cluster: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct DenominationConf {
+    #[serde(default)]
+    pub mode: DenominationMode,
+    #[serde(default)]
+    pub values: Vec<u64>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DenominationMode {
+    #[default]
+    Off,
+    Exact,
+    Range,
 }
 
 #[derive(Deserialize)]
@@ -30,15 +173,65 @@ pub struct Dust {
 #[derive(Deserialize)]
 pub struct Fee {
     pub range: f32,
+    pub boundaries: Option<Vec<f32>>,
+    pub geometric: Option<Geometric>,
+    // Confirmation targets (blocks) whose fee estimates are used as band boundaries
+    pub targets: Option<Vec<usize>>,
+    #[serde(default)]
+    pub overrides: Vec<BandOverride>,
+}
+
+#[derive(Deserialize)]
+pub struct Geometric {
+    pub start: f32,
+    pub ratio: f32,
+}
+
+#[derive(Deserialize)]
+pub struct BandOverride {
+    pub from: f32,
+    pub max_time: Option<i64>,
+    pub max_size: Option<usize>,
+    pub target: Option<usize>,
 }
 
 #[derive(Deserialize)]
 pub struct Server {
     pub ip: String,
     pub port: String,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    #[serde(default = "default_backend_threads")]
+    pub backend_threads: usize,
+    #[serde(default = "default_max_validations")]
+    pub max_validations: usize,
 }
 
+fn default_max_connections() -> usize { 4096 }
+fn default_idle_timeout() -> u64 { 300 }
+fn default_request_timeout() -> u64 { 60 }
+fn default_backend_threads() -> usize { 64 }
+fn default_max_validations() -> usize { 48 }
+
 #[derive(Deserialize)]
 pub struct Network {
     pub name: String,
 }
+
+#[derive(Deserialize)]
+pub struct TrackerConf {
+    #[serde(default = "default_confirmations")]
+    pub confirmations: u32,
+}
+
+impl Default for TrackerConf {
+    fn default() -> Self {
+        TrackerConf { confirmations: default_confirmations() }
+    }
+}
+
+fn default_confirmations() -> u32 { 6 }
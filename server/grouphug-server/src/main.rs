@@ -2,28 +2,50 @@
 mod utils;
 mod config;
 mod server;
-use crate::utils::transactions::validate_tx_query_one_to_one_single_anyone_can_pay;
+use crate::utils::transactions::{pairs_vsize, spent_outpoints, validate_tx_query_one_to_one_single_anyone_can_pay};
 use crate::config::Config;
-use crate::server::group::Group;
+use crate::server::group::{Group, ScriptClass, P2A_SCRIPT};
+use crate::server::bands;
+use crate::server::blocks::watch_tip;
+use crate::server::{channels, estimates};
+use crate::server::policy::CloseContext;
+use crate::server::registry::{Registry, SubmissionStatus};
+use crate::server::tracker::{self, Observed, TxState};
 
 // External libraries
 use std::{
     thread,
     time::Duration,
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
+    str::FromStr,
+    net::SocketAddr,
     str,
     fs,
     env,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, mpsc::sync_channel},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::Handle,
+    sync::{Notify, Semaphore},
+    task,
+    time::{sleep, timeout},
 };
 use chrono::Utc;
 use once_cell::sync::Lazy;
+use rand::{Rng, rngs::OsRng};
 use hex::decode as hex_decode;
-use bdk::bitcoin::{Transaction,consensus::encode::deserialize};
+use bdk::bitcoin::{
+    OutPoint,
+    Sequence,
+    Transaction,
+    TxIn,
+    Txid,
+    absolute::LockTime,
+    consensus::encode::deserialize,
+    psbt::PartiallySignedTransaction,
+};
 use bdk::electrum_client::{Client, ConfigBuilder, ElectrumApi};
-use bdk::blockchain::{ElectrumBlockchain};
-use bdk::{FeeRate};
 
 pub static CONFIG: Lazy<Config> = Lazy::new(|| {
     
@@ -32,13 +54,14 @@ pub static CONFIG: Lazy<Config> = Lazy::new(|| {
     // Default Config.toml is same dir as the bin
     let default_path = "Config.toml";
 
-    if args.len() > 2 {
+    if args.len() > 2 && !cfg!(test) {
         eprintln!("{}: Only 1 argument accepted", Utc::now());
         std::process::exit(1);
     }
 
     // If there's an argumment try to use it as config path
-    let config_path = if args.len() > 1 {
+    // The arguments of the tests are the ones of the test harness, they always use the default file
+    let config_path = if args.len() > 1 && !cfg!(test) {
         &args[1]
     } else {
         default_path
@@ -54,208 +77,422 @@ pub static CONFIG: Lazy<Config> = Lazy::new(|| {
 });
 
 
-// Array with Group list
-type GroupHug = Group;
-static GLOBAL_GROUPS: Lazy<Arc<Mutex<Vec<GroupHug>>>> = Lazy::new(|| Arc::new(Mutex::new(Vec::new())));
+// Open groups and the outpoints they reserve
+static GLOBAL_GROUPS: Lazy<Arc<Mutex<Registry>>> = Lazy::new(|| Arc::new(Mutex::new(Registry::new())));
 
-fn check_double_spending_other_group(tx_hex: &str) -> (bool, String) {
-    // Check if an input from a transaction is already duplicated on another group
-    // Return true if cheating is detected
-    /* REPLACE BY FEE NOT IMPLEMENTED ON GROUPS YET */
-    
-    
-    // Decode the transaction received
-    let tx_hex_decoded = match hex_decode(tx_hex) {
-        Ok(decoded) => decoded,
-        Err(_) => return (true, String::from("Error decoding hex")),
-    };
-    let tx: Transaction = match deserialize(&tx_hex_decoded) {
-        Ok(transaction) => transaction,
-        Err(_) => return (false, String::from("Error deserializing transaction")),
-    };
+// Set when a new block is found, until the groups are checked again
+static NEW_BLOCK: AtomicBool = AtomicBool::new(false);
 
-    
-    // Lock the global groups and iterate over them
-    let groups = GLOBAL_GROUPS.lock().unwrap();
-    
-    for txin in tx.input.iter(){
-        for group in groups.iter() {
-            // Checks if a tx input is in the group
-            if group.contains_txin(&txin) {
-                eprintln!("{}: Transaction was rejected, Error: transaction input is already in a group\n", Utc::now());
-                return (true, String::from("Transaction input is already in a group"));
+// Validations running on the blocking pool. A timed out validation keeps its permit until it really
+// ends, so a slow backend can not take the threads the group closes need
+static VALIDATIONS: Lazy<Arc<Semaphore>> = Lazy::new(|| Arc::new(Semaphore::new(crate::CONFIG.server.max_validations)));
+
+async fn handle_get_groups_info(stream: &mut TcpStream) {
+    let mut msg = String::new();
+
+    { // Build the answer first, the lock can not be held while writing to the client
+        let registry = GLOBAL_GROUPS.lock().unwrap();
+
+        if registry.groups.is_empty() {
+            msg.push_str("There's no groups\n");
+        }
+        else {
+            for group in registry.groups.iter() {
+                msg.push_str(&format!("Id: {}, Fee: {}, Size: {}/{}, Timestamp: {}", group.id, group.fee_rate, group.get_num_transactions(), bands::max_size(group.fee_rate), group.timestamp));
+                if group.bucket.version != 2 {
+                    msg.push_str(&format!(", Version: {}", group.bucket.version));
+                }
+                if group.bucket.input_class != ScriptClass::Any {
+                    msg.push_str(&format!(", Scripts: {:?} -> {:?}", group.bucket.input_class, group.bucket.output_class));
+                }
+                if group.bucket.denomination != 0 {
+                    msg.push_str(&format!(", Denomination: {}", group.bucket.denomination));
+                }
+                if group.bucket.lock_time != 0 {
+                    msg.push_str(&format!(", Locktime: {}", group.bucket.lock_time));
+                }
+                if let Some(target) = bands::target(group.fee_rate) {
+                    msg.push_str(&format!(", Target: {} blocks", target));
+                }
+                if let Some((target, estimate)) = group.stale {
+                    msg.push_str(&format!(", Warning: stale band, under the {} blocks estimate of {}sat/vB", target, estimate));
+                }
+                msg.push('\n');
             }
         }
-    }
-    
 
-    return (false, String::from("Ok\n"));
+        if registry.num_closing() > 0 {
+            msg.push_str(&format!("Closing: {}\n", registry.num_closing()));
+        }
+    }
 
+    msg.push_str("EOF\n");
+    stream.write_all(msg.as_bytes()).await.unwrap();
 }
 
-fn handle_get_groups_info(mut stream: TcpStream) {
-    let groups = GLOBAL_GROUPS.lock().unwrap();
-    
-    if groups.len() == 0 {
-        let msg = format!("There's no groups\n");
-        stream.write(msg.as_bytes()).unwrap();
+fn format_tx_state(state: &TxState) -> String {
+    match state {
+        TxState::Mempool => String::from("In mempool"),
+        TxState::Unconfirmed => String::from("Accepted but not seen in any mempool yet"),
+        TxState::Confirmed(height) => format!("Confirmed at height {}", height),
+        TxState::Dropped(reason) => format!("Dropped: {}", reason),
+        TxState::Rebuilt(txid) => format!("Rebuilt as {}", txid),
     }
-    else {
-        for group in groups.iter() {
-            let msg = format!("Fee: {}, Size: {}/{}, Timestamp: {}\n", group.fee_rate, group.get_num_transactions(), &crate::CONFIG.group.max_size, group.timestamp);
-            stream.write(msg.as_bytes()).unwrap();
-        }   
+}
+
+async fn handle_get_tx_status(txid: &str, stream: &mut TcpStream) {
+    // Tell the client where a transaction it submitted is
+    let txid = match Txid::from_str(txid) {
+        Ok(txid) => txid,
+        Err(_) => {
+            stream.write_all(b"Error: Invalid txid\n").await.unwrap();
+            return
+        }
+    };
+
+    let status = GLOBAL_GROUPS.lock().unwrap().submission_status(&txid);
+    let msg = match status {
+        SubmissionStatus::Pending(group_id, fee_rate) => format!("Pending in group {} with fee_rate {}sat/vB\n", group_id, fee_rate),
+        SubmissionStatus::Closing(group_id) => format!("Group {} is being closed\n", group_id),
+        SubmissionStatus::Broadcasted(group_id, group_txid, state) => format!("Group {} transaction {}: {}\n", group_id, group_txid, format_tx_state(&state)),
+        SubmissionStatus::Evicted(reason) => format!("Evicted from its group: {}\n", reason),
+        SubmissionStatus::NotBatched(reason) => format!("Not batched, {}. The transaction can be sent again or broadcasted alone\n", reason),
+        SubmissionStatus::Unknown => String::from("Unknown transaction\n"),
+    };
+    stream.write_all(msg.as_bytes()).await.unwrap();
+}
+
+async fn handle_get_history(stream: &mut TcpStream) {
+    // List the broadcasted group transactions, from the oldest to the newest
+    let mut msg = String::new();
+
+    {
+        let registry = GLOBAL_GROUPS.lock().unwrap();
+        for tracked in registry.tracker.history() {
+            let channels: Vec<String> = tracked.channels.iter().map(|(endpoint, result)| format!("{} {}", endpoint, result)).collect();
+            msg.push_str(&format!("Group: {}, Fee: {}, TXID: {}, Broadcasted: {}, Closed by: {}, Rebroadcasts: {}, Status: {}, Channels: {}\n", tracked.group_id, tracked.fee_rate, tracked.txid, tracked.broadcast_at, tracked.close_reason.as_deref().unwrap_or("-"), tracked.rebroadcasts, format_tx_state(&tracked.state), channels.join("; ")));
+        }
     }
 
-    let end_line = format!("EOF\n");
-    stream.write(end_line.as_bytes()).unwrap();
-    return
+    msg.push_str("EOF\n");
+    stream.write_all(msg.as_bytes()).await.unwrap();
 }
 
-fn close_group_by_fee() {
-    // Check the actual feerate for the network and close all groups that have a fee rate bigger than the actual fee rate by 2 sat/vb.
-    
-    let config = ConfigBuilder::new().validate_domain(crate::CONFIG.electrum.certificate_validation).build();
-    let client = Client::from_config(&crate::CONFIG.electrum.endpoint, config.clone()).unwrap();
-    let blockchain = ElectrumBlockchain::from(client);
-
-    let target: usize = 1;
-    let mut matching_fee_rates: Vec<f32> = Vec::new();
-
-    let mut groups = GLOBAL_GROUPS.lock().unwrap();
-    match blockchain.estimate_fee(target) {
-        Ok(rate) => {
-            let fee_rate = FeeRate::from_btc_per_kvb(rate as f32);
-            // compare needed fee rate for the target confirmation with the group fee rate
-            // close the ones that pay more than what is needed
-            for group in groups.iter_mut() {
-                if (fee_rate.as_sat_per_vb() as f32) < ((group.fee_rate - 2.0) as f32) {
-                    if group.close_group() {
-                        matching_fee_rates.push(group.fee_rate);
-                    }  
+async fn handle_get_bump_template(txid: &str, stream: &mut TcpStream) {
+    // Give what a participant needs to bump a stuck group transaction with CPFP: the anchor outpoint,
+    // the fee and size of the group transaction and an unsigned PSBT spending the anchor.
+    // The participant adds its own inputs and outputs to pay for the package
+    let txid = match Txid::from_str(txid) {
+        Ok(txid) => txid,
+        Err(_) => {
+            stream.write_all(b"Error: Invalid txid\n").await.unwrap();
+            return
+        }
+    };
+
+    let msg = {
+        let registry = GLOBAL_GROUPS.lock().unwrap();
+        match registry.tracker.get(&txid) {
+            None => String::from("Error: Unknown group transaction\n"),
+            Some(tracked) if tracked.state != TxState::Mempool => String::from("Error: Group transaction is not in the mempool\n"),
+            Some(tracked) => match bump_template(&tracked.tx) {
+                None => String::from("Error: Group transaction has no anchor output\n"),
+                Some((vout, psbt)) => {
+                    let fee = tracked.fees.values().sum::<u64>().saturating_sub(tracked.tx.output[vout].value);
+                    format!("Anchor: {}:{}\nValue: {}\nGroup vsize: {}\nGroup fee: {}\nPSBT: {}\nEOF\n", txid, vout, tracked.tx.output[vout].value, tracked.tx.vsize(), fee, psbt)
                 }
+            },
+        }
+    };
+    stream.write_all(msg.as_bytes()).await.unwrap();
+}
+
+fn bump_template(tx: &Transaction) -> Option<(usize, PartiallySignedTransaction)> {
+    // Build an unsigned child spending the anchor output of a group transaction.
+    // A child of a TRUC transaction has to be TRUC too, so it takes the version of the parent
+    let vout = tx.output.iter().position(|o| o.script_pubkey.as_bytes() == P2A_SCRIPT)?;
+    let child = Transaction {
+        version: tx.version,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(tx.txid(), vout as u32),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            ..Default::default()
+        }],
+        output: Vec::new(),
+    };
+    let mut psbt = PartiallySignedTransaction::from_unsigned_tx(child).ok()?;
+    psbt.inputs[0].witness_utxo = Some(tx.output[vout].clone());
+    Some((vout, psbt))
+}
+
+fn track_group_transactions() {
+    // Check where the broadcasted group transactions are, broadcast again the ones that dropped from the mempool
+    let watched = GLOBAL_GROUPS.lock().unwrap().tracker.watched();
+    if watched.is_empty() {
+        return;
+    }
+
+    let client = match channels::connect_any() {
+        Some(client) => client,
+        None => return,
+    };
+    let tip = match client.block_headers_subscribe() {
+        Ok(header) => header.height as u32,
+        Err(e) => {
+            eprintln!("{}: Could not get the chain tip: {:?}", Utc::now(), e);
+            return;
+        }
+    };
+
+    for (txid, tx) in watched {
+        match tracker::observe(&client, &tx) {
+            Ok(Observed::Missing) => {
+                println!("{}: Group transaction {} is not in the mempool, broadcasting it again", Utc::now(), txid);
+                let (result, channels) = tracker::rebroadcast(&tx);
+                GLOBAL_GROUPS.lock().unwrap().tracker.record_channels(&txid, channels);
+                match result {
+                    Ok(seen) => GLOBAL_GROUPS.lock().unwrap().tracker.rebroadcast_result(&txid, Ok(seen)),
+                    Err(Some(reason)) => {
+                        GLOBAL_GROUPS.lock().unwrap().tracker.rebroadcast_result(&txid, Err(reason));
+                        rebuild_dropped_group(&client, &txid, &tx);
+                    },
+                    Err(None) => (),
+                }
+            },
+            Ok(observed) => GLOBAL_GROUPS.lock().unwrap().tracker.update(&txid, observed, tip),
+            Err(e) => {
+                eprintln!("{}: Could not check group transaction {}: {:?}", Utc::now(), txid, e);
             }
-        },
+        }
+    }
+}
+
+fn rebuild_dropped_group(client: &Client, txid: &Txid, tx: &Transaction) {
+    // A group transaction that can not be broadcasted again usually means some participant double
+    // spent its input. Rebuild the group without those pairs and broadcast it so the others keep their batching.
+    // The dropped transaction is watched until this is done, so it is tried again on the next check if it fails
+    let conflicting = match spent_outpoints(client, tx) {
+        Ok(conflicting) => conflicting,
         Err(e) => {
-            eprintln!("{}: There was an error estimating fees for the next {:?} blocks: {:?}",Utc::now(), target, e);
+            eprintln!("{}: Could not check the inputs of the dropped group transaction {}, trying again later: {}", Utc::now(), txid, e);
+            return;
         }
+    };
+    if conflicting.is_empty() {
+        // Nothing to remove, it would be rebuilt exactly the same
+        GLOBAL_GROUPS.lock().unwrap().tracker.finish(txid);
+        return;
     }
 
-    for rate in matching_fee_rates {
-        // delete the closed groups from the group list 
-        groups.retain(|g| g.fee_rate != rate);
+    let group = GLOBAL_GROUPS.lock().unwrap().rebuild_dropped(txid, &conflicting);
+    if let Some(group) = group {
+        close_detached_groups(vec![group]);
     }
-    return;    
-    
 }
 
-fn handle_addtx(transaction: &str, mut stream: TcpStream) {
+fn close_detached_groups(groups: Vec<Group>) {
+    // Close groups already detached from the registry.
+    // The checks and the broadcast run without holding the GLOBAL_GROUPS lock, it is only taken
+    // again to report the result of each group.
+    // With a broadcast delay each group waits a random time on the runtime and is then closed on the
+    // blocking pool, so the broadcast can not be matched with the submission or the moment that closed the group.
+    // It is always called from the blocking pool, where the runtime handle is available
+    let max_delay = crate::CONFIG.close.broadcast_delay;
+    for mut group in groups {
+        if max_delay == 0 {
+            let closed = group.close_group();
+            GLOBAL_GROUPS.lock().unwrap().finish_closing(group, closed);
+            continue;
+        }
 
-    // Validate that the tx has the correct format and satisfies all the rules
-    let (valid, msg, fee_rate) = validate_tx_query_one_to_one_single_anyone_can_pay(transaction);
+        let delay = OsRng.gen_range(0..=max_delay);
+        println!("{}: Group {} will be broadcasted in {}s", Utc::now(), group.id, delay);
+        Handle::current().spawn(async move {
+            sleep(Duration::from_secs(delay)).await;
+            task::spawn_blocking(move || {
+                let closed = group.close_group();
+                GLOBAL_GROUPS.lock().unwrap().finish_closing(group, closed);
+            });
+        });
+    }
+}
+
+fn close_groups_by_policy() {
+    // Ask the Electrum server for the fee rates needed by the confirmation targets of the groups,
+    // then close every group the close policy says must be closed
+
+    let config = ConfigBuilder::new().validate_domain(crate::CONFIG.electrum.certificate_validation).build();
+    let client = match Client::from_config(&crate::CONFIG.electrum.endpoint, config) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{}: Could not connect to the Electrum server: {:?}", Utc::now(), e);
+            return;
+        }
+    };
+
+    let new_block = NEW_BLOCK.swap(false, Ordering::SeqCst);
+
+    // Fetch once the estimates of every target needed in this tick: the ones of the dynamic bands,
+    // the overrides, the default and the ones of the open groups
+    let mut targets: Vec<usize> = GLOBAL_GROUPS.lock().unwrap().groups.iter().map(|g| bands::confirmation_target(g.fee_rate)).collect();
+    targets.extend(crate::CONFIG.fee.targets.iter().flatten().copied());
+    targets.extend(crate::CONFIG.fee.overrides.iter().filter_map(|o| o.target));
+    targets.push(crate::CONFIG.close.target);
+    targets.sort();
+    targets.dedup();
+    let fee_estimates = estimates::estimates(&client, &targets, new_block);
+
+    // Follow the mempool with the fee bands, the open groups are moved to the new ones
+    if let Some(targets) = &crate::CONFIG.fee.targets {
+        let ladder: Vec<(usize, f32)> = targets.iter().filter_map(|t| fee_estimates.get(t).map(|rate| (*t, *rate))).collect();
+        if bands::update(&ladder) {
+            GLOBAL_GROUPS.lock().unwrap().rebalance_bands();
+        }
+    }
+
+    let ctx = CloseContext {
+        now: Utc::now().timestamp(),
+        fee_estimates,
+        new_block,
+    };
+    let detached = {
+        let mut registry = GLOBAL_GROUPS.lock().unwrap();
+        registry.update_stale(&ctx.fee_estimates, ctx.now);
+        registry.merge_underfilled(ctx.now);
+        registry.release_below_minimum(ctx.now);
+        registry.detach_by_policy(&ctx)
+    };
+    close_detached_groups(detached);
+}
+
+async fn handle_addtx(transaction: &str, stream: &mut TcpStream, peer: SocketAddr, request_timeout: Duration) {
 
-    println!("{}: Client {} sent a new raw transaction: {}", Utc::now(), stream.peer_addr().unwrap(), transaction);
+    println!("{}: Client {} sent a new raw transaction: {}", Utc::now(), peer, transaction);
+
+    // Validate that the tx has the correct format and satisfies all the rules
+    // The validation queries the Electrum server, so it runs on the blocking pool.
+    // Only the validation is bounded by the request timeout, once the registry has been changed the
+    // client always gets the real outcome
+    let permit = match VALIDATIONS.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_e) => {
+            eprintln!("{}: Validation limit reached, rejecting the transaction from client {}\n", Utc::now(), peer);
+            stream.write_all(b"Error: Server busy, try again later\n").await.unwrap();
+            return;
+        }
+    };
+    let tx_hex = transaction.to_string();
+    let validation = task::spawn_blocking(move || {
+        let result = validate_tx_query_one_to_one_single_anyone_can_pay(&tx_hex);
+        drop(permit);
+        result
+    });
+    let (valid, msg, fee) = match timeout(request_timeout, validation).await {
+        Ok(result) => result.unwrap(),
+        Err(_elapsed) => {
+            eprintln!("{}: Validation of the transaction from client {} timed out\n", Utc::now(), peer);
+            stream.write_all(b"Error: Request timed out\n").await.unwrap();
+            return;
+        }
+    };
 
     if !valid {
         // should send an error message as the transaction has an invalid format or does not match some rule
         let error_msg = format!("Error: {}\n", msg);
         eprintln!("{}: Transaction was rejected, {}\n", Utc::now(), error_msg);
-        stream.write(error_msg.as_bytes()).unwrap();
+        stream.write_all(error_msg.as_bytes()).await.unwrap();
         return
     }
 
-
     
-    let (double_spend, msg) = check_double_spending_other_group(transaction);
-    if double_spend {
-        // should send an error as we detected that the tx input has been already added to another group
-        let error_msg = format!("Error: {}\n", msg);
-        stream.write(error_msg.as_bytes()).unwrap();
-        return
-    }
+    // The validation already decoded it, so this can not fail
+    let tx: Transaction = deserialize(&hex_decode(transaction).unwrap()).unwrap();
 
-    // Calculate the group fee rate.
-    let expected_group_fee = ((fee_rate / &crate::CONFIG.fee.range).floor() * &crate::CONFIG.fee.range) as f32;
+    // Calculate the group fee rate from the exact fee of the pairs
+    let fee_rate = fee as f32 / pairs_vsize(&tx);
+    let expected_group_fee = bands::band_for(fee_rate);
 
-    { // Use this so we unlock the GLOBAL_GROUPS variable after using it
+    // Lock the GLOBAL_GROUPS variable
+    // The double spending check against the other groups and the insertion happen under the same lock
+    let result = GLOBAL_GROUPS.lock().unwrap().add_tx(&tx, expected_group_fee, fee, peer);
 
-        // Unlock the GLOBAL_GROUPS variable
-        let mut groups = GLOBAL_GROUPS.lock().unwrap();
+    match result {
+        Ok(detached) => {
+            // Send an OK message if the tx was added successfuly
+            stream.write_all(b"Ok\n").await.unwrap();
 
-        // Search for the group corresponing to the transaction fee rate
-        let group = groups.iter_mut().find(|g| g.fee_rate == expected_group_fee);
-
-        let close_group;
-        match group {
-            Some(group) => {
-                // If some then the group already exist so we add the tx to that group
-                close_group = group.add_tx(transaction);
-            },
-            None => {
-                // If none then there is no group for this fee rate so we create one
-                let mut new_group = Group::new(expected_group_fee);
-                println!("{}: New group created with fee_rate {}sat/vB", Utc::now(), new_group.fee_rate);
-                close_group = new_group.add_tx(transaction);
-                groups.push(new_group);
+            // Groups that have to be closed have been detached, close them once the client already has its answer
+            if !detached.is_empty() {
+                task::spawn_blocking(move || close_detached_groups(detached));
             }
-        }
-
-        if close_group {
-            // If the group has been closed during the add_tx function we delete it from the groups vector
-            groups.retain(|g| g.fee_rate != expected_group_fee);
+        },
+        Err(msg) => {
+            // should send an error as we detected that the tx input has been already added to another group
+            let error_msg = format!("Error: {}\n", msg);
+            stream.write_all(error_msg.as_bytes()).await.unwrap();
         }
     }
-
-    // Send an OK message if the tx was added successfuly
-    stream.write(msg.as_bytes()).unwrap();
-
-    return;
 }
 
 
-fn handle_client(mut stream: TcpStream) {
+async fn handle_client(mut stream: TcpStream, peer: SocketAddr) {
 
-    println!("{}: New user connected: {}\n", Utc::now(), stream.peer_addr().unwrap());
+    println!("{}: New user connected: {}\n", Utc::now(), peer);
 
     // send the network configuration
     // TODO -> Find a way to ask the electrum server what network is running
     if &crate::CONFIG.network.name == "testnet" {
-        stream.write(b"TESTNET\n").unwrap();
+        stream.write_all(b"TESTNET\n").await.unwrap();
     }
     else if &crate::CONFIG.network.name == "mainnet" {
-        stream.write(b"MAINNET\n").unwrap();
+        stream.write_all(b"MAINNET\n").await.unwrap();
     }
     else if &crate::CONFIG.network.name == "signet" {
-        stream.write(b"SIGNET\n").unwrap();
+        stream.write_all(b"SIGNET\n").await.unwrap();
     } 
     
-    // 100KB size for large transactions
-    let mut buffer = [0; 100*1024]; 
+    let idle_timeout = Duration::from_secs(crate::CONFIG.server.idle_timeout);
+    let request_timeout = Duration::from_secs(crate::CONFIG.server.request_timeout);
+
     loop {
-        let nbytes = stream.read(&mut buffer).unwrap();
+        // Wait for the next request without holding a buffer, idle connections cost almost nothing
+        match timeout(idle_timeout, stream.readable()).await {
+            Ok(Ok(())) => (),
+            Ok(Err(_e)) => return,
+            Err(_elapsed) => {
+                println!("{}: Client {} has been idle for too long, disconnecting\n", Utc::now(), peer);
+                return;
+            }
+        }
+
+        // 100KB size for large transactions, only allocated while a request is being handled
+        let mut buffer = vec![0; 100*1024];
+        let nbytes = stream.read(&mut buffer).await.unwrap_or(0);
         if nbytes == 0 {
             return;
         }
+        buffer.truncate(nbytes);
 
-        let command_string = match String::from_utf8(buffer[0..nbytes].to_vec()) {
+        let command_string = match String::from_utf8(buffer) {
             Ok(s) => s,
             Err(_e) => {
                 // If error user has disconnected
-                println!("{}: Client {} disconnected\n", Utc::now(), stream.peer_addr().unwrap());
+                println!("{}: Client {} disconnected\n", Utc::now(), peer);
                 return;
             },
         };
         
 
 
-        let command_parts: Vec<&str> = command_string.trim().split_whitespace().collect();
+        let command_parts: Vec<&str> = command_string.split_whitespace().collect();
         
         
-        if command_parts.len() > 2 {
+        if command_parts.is_empty() || command_parts.len() > 2 {
             // If there's more than two arguments on the call something is worng.
             // Expected format: "add_tx raw_tx_data"
-            eprintln!("{}: Client {} sent a command with wrong number of arguments: {}\n", Utc::now(), stream.peer_addr().unwrap(), command_string.trim());
-            stream.write(b"One or two arguments are expected\n").unwrap();
+            eprintln!("{}: Client {} sent a command with wrong number of arguments: {}\n", Utc::now(), peer, command_string.trim());
+            stream.write_all(b"One or two arguments are expected\n").await.unwrap();
             continue;
         }
         let command;
@@ -267,66 +504,93 @@ fn handle_client(mut stream: TcpStream) {
             command = command_parts[0];
         }
 
-        match command {
-            // This allows to add more commands in the future
-            "add_tx" => handle_addtx(arg, stream.try_clone().unwrap()),
-            "get_groupsInfo" => handle_get_groups_info(stream.try_clone().unwrap()),
-            _ => {
-                eprintln!("{}: Client {} sent an unknown command: {}\n", Utc::now(), stream.peer_addr().unwrap(), command);
-                stream.write(b"Unknown command sent\n").unwrap();
-            },
+        // Adding a transaction bounds its own validation by the deadline, it must not be cancelled
+        // once the transaction is in a group
+        if command == "add_tx" {
+            handle_addtx(arg, &mut stream, peer, request_timeout).await;
+            continue;
         }
-    }
-}
-
-fn close_group_by_time(){
-    // Check that the creation timestamp of a group + the max_time (in secs) is lower than the actual time, if not, close the group
-    let actual_time: i64 = Utc::now().timestamp();
 
-    let mut groups = GLOBAL_GROUPS.lock().unwrap();
-
-    // Save the feerate as an Id to identify the groups closed
-    let mut groups_closed: Vec<f32> = Vec::new();
-    
-    for group in groups.iter_mut() {
-        if group.timestamp + &crate::CONFIG.group.max_time <= actual_time {
-            if group.close_group() {
-                groups_closed.push(group.fee_rate);
-            }  
+        // Every other request must be answered before the deadline
+        let request = async {
+            match command {
+                // This allows to add more commands in the future
+                "get_groupsInfo" => handle_get_groups_info(&mut stream).await,
+                "get_txStatus" => handle_get_tx_status(arg, &mut stream).await,
+                "get_history" => handle_get_history(&mut stream).await,
+                "get_bumpTemplate" => handle_get_bump_template(arg, &mut stream).await,
+                _ => {
+                    eprintln!("{}: Client {} sent an unknown command: {}\n", Utc::now(), peer, command);
+                    stream.write_all(b"Unknown command sent\n").await.unwrap();
+                },
+            }
+        };
+        if timeout(request_timeout, request).await.is_err() {
+            eprintln!("{}: Request from client {} timed out: {}\n", Utc::now(), peer, command);
+            stream.write_all(b"Error: Request timed out\n").await.unwrap();
         }
     }
-
-    // delete the closed groups from the group list 
-    for rate in groups_closed {
-        groups.retain(|g| g.fee_rate != rate);
-    }
-
-    return;
-
 }
 
-fn main() {
-           
+async fn run_server() {
+
     // Fromat endpoint data from config file
     let endpoint: String = format!("{}:{}", &crate::CONFIG.server.ip, &crate::CONFIG.server.port);
     
-    let listener = TcpListener::bind(endpoint.clone()).unwrap();
+    let listener = TcpListener::bind(endpoint.clone()).await.unwrap();
+
+    // Follow the chain tip, each new block wakes up the group closer
+    let block_found = Arc::new(Notify::new());
+    let notifier = block_found.clone();
+    thread::spawn(move || {
+        watch_tip(|_height| {
+            NEW_BLOCK.store(true, Ordering::SeqCst);
+            notifier.notify_one();
+        });
+    });
 
-    // Check if need to close groups because of time conditions every 30seconds
-    thread::spawn(|| {
+    // Check if need to close groups according to the close policy every 60 seconds, plus a random
+    // jitter, or when a new block is found, and follow the group transactions already broadcasted.
+    // The closer runs on its own thread so the client validations can never keep it waiting for the pool
+    let (tick, ticks) = sync_channel::<()>(1);
+    let handle = Handle::current();
+    thread::spawn(move || {
+        let _runtime = handle.enter();
+        for () in ticks {
+            close_groups_by_policy();
+            track_group_transactions();
+        }
+    });
+    tokio::spawn(async move {
         loop {
-            close_group_by_time();
-            close_group_by_fee();
-            thread::sleep(Duration::from_secs(60));
+            let jitter = OsRng.gen_range(0..=crate::CONFIG.close.tick_jitter);
+            tokio::select! {
+                _ = sleep(Duration::from_secs(60 + jitter)) => (),
+                _ = block_found.notified() => (),
+            }
+            // A tick while the closer is still busy is skipped, it checks everything on the next one
+            let _ = tick.try_send(());
         }
     });
 
+    // Each connection holds a permit for its whole life
+    let connections = Arc::new(Semaphore::new(crate::CONFIG.server.max_connections));
+
     println!("{}: Server running on {}", Utc::now(), endpoint);
-    for stream in listener.incoming(){
-        match stream {
-            Ok(stream) => {
-                thread::spawn(|| {
-                    handle_client(stream);
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, peer)) => {
+                let permit = match connections.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_e) => {
+                        eprintln!("{}: Connection limit reached, rejecting client {}", Utc::now(), peer);
+                        let _ = stream.write_all(b"Error: Server busy, try again later\n").await;
+                        continue;
+                    }
+                };
+                tokio::spawn(async move {
+                    handle_client(stream, peer).await;
+                    drop(permit);
                 });
             }
             Err(e) => {
@@ -335,3 +599,16 @@ fn main() {
         }
     }
 }
+
+fn main() {
+
+    // Electrum calls are blocking, they run on a bounded pool of threads separated from the
+    // ones serving the connections
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .max_blocking_threads(crate::CONFIG.server.backend_threads)
+        .build()
+        .unwrap();
+
+    runtime.block_on(run_server());
+}
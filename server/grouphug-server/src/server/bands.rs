@@ -0,0 +1,113 @@
+//! Fee bands the groups are split in. A transaction goes to the band with the highest lower bound
+//! under its fee rate, and the group fee rate is that lower bound.
+//! Bands can be uniform (`range`), an explicit list of boundaries or a geometric ladder,
+//! and can override the group max_time and max_size.
+//! With confirmation targets the boundaries follow the fee estimates of the backend and change
+//! while the server runs.
+
+use std::sync::RwLock;
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+
+use crate::config::BandOverride;
+
+// Lower bound and confirmation target of each dynamic band, from the lowest to the highest fee rate.
+// Empty until the first estimates arrive
+static DYNAMIC: Lazy<RwLock<Vec<(f32, usize)>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+pub fn band_for(fee_rate: f32) -> f32 {
+    // Return the lower bound of the band the fee rate belongs to. Fee rates under the first band are in band 0
+    let fee_conf = &crate::CONFIG.fee;
+
+    {
+        let dynamic = DYNAMIC.read().unwrap();
+        if !dynamic.is_empty() {
+            return dynamic.iter()
+                .map(|(lower, _)| *lower)
+                .filter(|b| *b <= fee_rate)
+                .fold(0.0, f32::max);
+        }
+    }
+
+    if let Some(boundaries) = &fee_conf.boundaries {
+        return boundaries.iter()
+            .copied()
+            .filter(|b| *b <= fee_rate)
+            .fold(0.0, f32::max);
+    }
+
+    if let Some(geometric) = &fee_conf.geometric {
+        if fee_rate < geometric.start || geometric.ratio <= 1.0 {
+            return 0.0;
+        }
+        let mut lower = geometric.start;
+        while lower * geometric.ratio <= fee_rate {
+            lower *= geometric.ratio;
+        }
+        // Keep two decimals, rounding down so the band never claims more than the transactions pay
+        return (lower * 100.0).floor() / 100.0;
+    }
+
+    (fee_rate / fee_conf.range).floor() * fee_conf.range
+}
+
+fn band_override(band: f32) -> Option<&'static BandOverride> {
+    // Return the override with the highest `from` that applies to the band, if any
+    crate::CONFIG.fee.overrides.iter()
+        .filter(|o| o.from <= band)
+        .max_by(|a, b| a.from.total_cmp(&b.from))
+}
+
+pub fn max_time(band: f32) -> i64 {
+    // Return the seconds a group of the band can be open
+    band_override(band)
+        .and_then(|o| o.max_time)
+        .unwrap_or(crate::CONFIG.group.max_time)
+}
+
+pub fn max_size(band: f32) -> usize {
+    // Return the number of pairs that closes a group of the band
+    band_override(band)
+        .and_then(|o| o.max_size)
+        .unwrap_or(crate::CONFIG.group.max_size)
+}
+
+pub fn target(band: f32) -> Option<usize> {
+    // Return the confirmation target of a dynamic band, if the band is one
+    DYNAMIC.read().unwrap().iter().find(|(lower, _)| *lower == band).map(|(_, target)| *target)
+}
+
+pub fn update(estimates: &[(usize, f32)]) -> bool {
+    // Set the dynamic bands from the fee estimate (sat/vB) of each confirmation target.
+    // Targets with the same estimate share the band of the fastest one.
+    // Return true if the boundaries changed
+    let mut ladder: Vec<(f32, usize)> = Vec::new();
+    for (target, estimate) in estimates.iter() {
+        if *estimate <= 0.0 {
+            continue;
+        }
+        let lower = (estimate * 100.0).floor() / 100.0;
+        match ladder.iter_mut().find(|(l, _)| *l == lower) {
+            Some(band) => band.1 = band.1.min(*target),
+            None => ladder.push((lower, *target)),
+        }
+    }
+    ladder.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut dynamic = DYNAMIC.write().unwrap();
+    if ladder.is_empty() || *dynamic == ladder {
+        return false;
+    }
+    let description: Vec<String> = ladder.iter().map(|(lower, target)| format!("{}sat/vB ({} blocks)", lower, target)).collect();
+    println!("{}: Fee bands updated: {}", Utc::now(), description.join(", "));
+    *dynamic = ladder;
+    true
+}
+
+pub fn confirmation_target(band: f32) -> usize {
+    // Return the number of blocks the groups of the band aim to confirm in
+    target(band)
+        .or_else(|| band_override(band).and_then(|o| o.target))
+        .unwrap_or(crate::CONFIG.close.target)
+}
@@ -0,0 +1,84 @@
+//! Follow the chain tip through the Electrum headers subscription, so groups can be checked
+//! right after a block is found instead of waiting for the next periodic check.
+
+use std::{thread, time::Duration, sync::atomic::{AtomicU32, Ordering}};
+
+use bdk::electrum_client::{Client, ConfigBuilder, ElectrumApi};
+use chrono::Utc;
+
+// Notifications are only read from the socket when there is some traffic, so the server is pinged this often
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+// Time to wait before connecting again after losing the connection
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+// Height of the last block seen, 0 until the first one
+static TIP: AtomicU32 = AtomicU32::new(0);
+
+pub fn tip() -> Option<u32> {
+    // Return the height of the chain tip, if it is already known
+    match TIP.load(Ordering::SeqCst) {
+        0 => None,
+        height => Some(height),
+    }
+}
+
+pub fn watch_tip<F>(on_new_tip: F)
+where
+    F: Fn(u32),
+{
+    // Call on_new_tip with the height of every new block. Never returns, it reconnects on errors
+    loop {
+        let config = ConfigBuilder::new().validate_domain(crate::CONFIG.electrum.certificate_validation).build();
+        let client = match Client::from_config(&crate::CONFIG.electrum.endpoint, config) {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("{}: Could not connect to the Electrum server to follow new blocks: {:?}", Utc::now(), e);
+                thread::sleep(RETRY_INTERVAL);
+                continue;
+            }
+        };
+
+        let mut tip = match client.block_headers_subscribe() {
+            Ok(header) => header.height,
+            Err(e) => {
+                eprintln!("{}: Could not subscribe to new blocks: {:?}", Utc::now(), e);
+                thread::sleep(RETRY_INTERVAL);
+                continue;
+            }
+        };
+        TIP.store(tip as u32, Ordering::SeqCst);
+        println!("{}: Following new blocks from height {}", Utc::now(), tip);
+
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            if let Err(e) = client.ping() {
+                eprintln!("{}: Lost the connection following new blocks: {:?}", Utc::now(), e);
+                break;
+            }
+
+            // Several blocks may have been found since the last check, only the last one matters
+            let mut new_tip = None;
+            loop {
+                match client.block_headers_pop() {
+                    Ok(Some(header)) => new_tip = Some(header.height),
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!("{}: Could not read new blocks: {:?}", Utc::now(), e);
+                        break;
+                    }
+                }
+            }
+
+            if let Some(height) = new_tip {
+                if height != tip {
+                    tip = height;
+                    TIP.store(tip as u32, Ordering::SeqCst);
+                    println!("{}: New block found at height {}", Utc::now(), tip);
+                    on_new_tip(tip as u32);
+                }
+            }
+        }
+
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
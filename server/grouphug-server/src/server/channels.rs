@@ -0,0 +1,147 @@
+//! Broadcast the group transactions through several independent Electrum servers at once, each one
+//! optionally behind a SOCKS5 proxy (e.g. Tor). No single server learns alone where the transactions
+//! come from, and a server that is down or silently drops them does not stall the group.
+//! The result of every channel is recorded, and a broadcast only counts as seen once some server has
+//! the transaction.
+
+use std::thread;
+use std::time::Duration;
+
+use bdk::bitcoin::{Transaction, Txid};
+use bdk::electrum_client::{Client, ConfigBuilder, ElectrumApi, Error as ElectrumError, Socks5Config};
+use chrono::Utc;
+
+// Time given to the servers to relay an accepted transaction before checking their mempools
+const SEEN_CHECK_DELAY: Duration = Duration::from_secs(2);
+
+// Endpoint of each channel and what happened when broadcasting through it
+pub type ChannelResults = Vec<(String, String)>;
+
+// Transaction accepted by some server, and whether some server has it in its mempool afterwards
+pub struct Accepted {
+    pub txid: Txid,
+    pub seen: bool,
+}
+
+struct Endpoint {
+    endpoint: String,
+    certificate_validation: bool,
+    socks5: Option<String>,
+}
+
+fn endpoints() -> Vec<Endpoint> {
+    // The main Electrum server followed by every configured channel
+    let mut endpoints = vec![Endpoint {
+        endpoint: crate::CONFIG.electrum.endpoint.clone(),
+        certificate_validation: crate::CONFIG.electrum.certificate_validation,
+        socks5: None,
+    }];
+    endpoints.extend(crate::CONFIG.channel.iter().map(|channel| Endpoint {
+        endpoint: channel.endpoint.clone(),
+        certificate_validation: channel.certificate_validation,
+        socks5: channel.socks5.clone(),
+    }));
+    endpoints
+}
+
+fn connect(endpoint: &Endpoint) -> Result<Client, ElectrumError> {
+    let config = ConfigBuilder::new()
+        .validate_domain(endpoint.certificate_validation)
+        .socks5(endpoint.socks5.as_deref().map(Socks5Config::new))
+        .build();
+    Client::from_config(&endpoint.endpoint, config)
+}
+
+pub fn connect_any() -> Option<Client> {
+    // Connect to the main Electrum server, or to the first channel that can be reached if it is down
+    for endpoint in endpoints() {
+        match connect(&endpoint) {
+            Ok(client) => return Some(client),
+            Err(e) => eprintln!("{}: Could not connect to the Electrum server {}: {:?}", Utc::now(), endpoint.endpoint, e),
+        }
+    }
+    None
+}
+
+enum Sent {
+    Accepted,
+    Rejected(String),
+    Unreachable,
+}
+
+fn send(endpoint: &Endpoint, tx: &Transaction) -> (Sent, Option<Client>) {
+    // Connect to a server and broadcast the transaction through it
+    let client = match connect(endpoint) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{}: Could not connect to the broadcast channel {}: {:?}", Utc::now(), endpoint.endpoint, e);
+            return (Sent::Unreachable, None);
+        }
+    };
+    let sent = match client.transaction_broadcast(tx) {
+        Ok(_) => Sent::Accepted,
+        Err(ElectrumError::Protocol(reason)) => Sent::Rejected(reason.to_string()),
+        Err(e) => {
+            eprintln!("{}: There is an error broadcasting {} through {}: {:?}", Utc::now(), tx.txid(), endpoint.endpoint, e);
+            Sent::Unreachable
+        }
+    };
+    (sent, Some(client))
+}
+
+pub fn broadcast(tx: &Transaction) -> (Result<Accepted, Option<String>>, ChannelResults) {
+    // Broadcast a transaction through the main Electrum server and every configured channel, all of
+    // them at the same time so none sees it first. None of them is required to be up.
+    // Returns the txid if some server accepted it, otherwise the reason if it was rejected or None
+    // if no server could be reached, together with the result of each channel
+    let txid = tx.txid();
+    let endpoints = endpoints();
+
+    let sent: Vec<(Sent, Option<Client>)> = thread::scope(|scope| {
+        let handles: Vec<_> = endpoints.iter().map(|endpoint| scope.spawn(move || send(endpoint, tx))).collect();
+        handles.into_iter().map(|handle| handle.join().unwrap_or((Sent::Unreachable, None))).collect()
+    });
+
+    let accepted = sent.iter().any(|(s, _)| matches!(s, Sent::Accepted));
+    let rejection = sent.iter().find_map(|(s, _)| match s {
+        Sent::Rejected(reason) => Some(reason.clone()),
+        _ => None,
+    });
+
+    // An accepted transaction should be in the mempool of the servers after a moment, check which ones have it
+    let seen: Vec<bool> = if accepted {
+        thread::sleep(SEEN_CHECK_DELAY);
+        thread::scope(|scope| {
+            let handles: Vec<_> = sent.iter()
+                .map(|(_, client)| scope.spawn(move || client.as_ref().map(|c| c.transaction_get(&txid).is_ok()).unwrap_or(false)))
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap_or(false)).collect()
+        })
+    }
+    else {
+        vec![false; sent.len()]
+    };
+
+    let results: ChannelResults = endpoints.iter()
+        .zip(sent.iter().zip(seen.iter()))
+        .map(|(endpoint, ((s, _), seen))| {
+            let result = match s {
+                Sent::Accepted if *seen => String::from("accepted, seen in mempool"),
+                Sent::Accepted => String::from("accepted, not seen"),
+                Sent::Rejected(reason) => format!("rejected: {}", reason),
+                Sent::Unreachable => String::from("unreachable"),
+            };
+            (endpoint.endpoint.clone(), result)
+        })
+        .collect();
+
+    if !accepted {
+        return (Err(rejection), results);
+    }
+
+    let seen = seen.iter().any(|s| *s);
+    if !seen {
+        eprintln!("{}: Transaction {} was accepted but no server has it, it is unconfirmed and will be broadcasted again if it does not show up", Utc::now(), txid);
+    }
+    (Ok(Accepted { txid, seen }), results)
+}
@@ -0,0 +1,49 @@
+//! Fee estimates of the backend for each confirmation target, cached across the checks of the groups.
+//! An estimate is asked again once it is older than the configured time or a new block is found,
+//! and the last known one is used while the backend can not give a new one.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bdk::FeeRate;
+use bdk::electrum_client::{Client, ElectrumApi};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+
+// Last estimate (sat/vB) of each confirmation target and when it was received
+static CACHE: Lazy<Mutex<HashMap<usize, (f32, i64)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn estimates(client: &Client, targets: &[usize], new_block: bool) -> HashMap<usize, f32> {
+    // Return the fee estimate of each target, refreshing the ones that are too old
+    let now = Utc::now().timestamp();
+    let mut cache = CACHE.lock().unwrap();
+    let mut estimates: HashMap<usize, f32> = HashMap::new();
+
+    for target in targets.iter() {
+        let cached = cache.get(target).copied();
+        let fresh = cached.filter(|(_, at)| !new_block && *at + crate::CONFIG.close.estimate_ttl > now);
+
+        let estimate = match fresh {
+            Some((rate, _)) => Some(rate),
+            None => match client.estimate_fee(*target) {
+                // Electrum answers -1 when it has no estimate for the target
+                Ok(rate) if rate > 0.0 => {
+                    let rate = FeeRate::from_btc_per_kvb(rate as f32).as_sat_per_vb();
+                    cache.insert(*target, (rate, now));
+                    Some(rate)
+                },
+                Ok(_) => cached.map(|(rate, _)| rate),
+                Err(e) => {
+                    eprintln!("{}: There was an error estimating fees for the next {:?} blocks: {:?}", Utc::now(), target, e);
+                    cached.map(|(rate, _)| rate)
+                }
+            },
+        };
+
+        if let Some(rate) = estimate {
+            estimates.insert(*target, rate);
+        }
+    }
+
+    estimates
+}
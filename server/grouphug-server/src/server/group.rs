@@ -1,97 +1,479 @@
 //! Logic related to the Groups, the components in charge of managing groups and making sure groups are closed properly when is required.
+// The functions of this module end with an explicit return
+#![allow(clippy::needless_return)]
 
-use hex::decode as hex_decode;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 
 use bdk::bitcoin::{
+    OutPoint,
     Transaction,
     TxIn,
     TxOut,
+    Txid,
+    ScriptBuf,
     blockdata::locktime::absolute::LockTime,
-    consensus::encode::deserialize, 
     consensus::encode::serialize_hex
 };
-use bdk::electrum_client::{Client, ConfigBuilder, ElectrumApi};
+use bdk::electrum_client::ElectrumApi;
 use bdk::blockchain::{ElectrumBlockchain, GetTx};
 use chrono::Utc;
+use rand::{rngs::OsRng, seq::SliceRandom};
+
+use crate::config::DenominationMode;
+use crate::server::bands;
+use crate::server::channels::{self, ChannelResults};
+use crate::utils::bitcoind::test_mempool_accept;
+use crate::utils::transactions::pair_sigops_cost;
+
+// Standardness limits of Bitcoin Core, a group transaction over them would not be relayed
+pub const MAX_STANDARD_TX_WEIGHT: usize = 400_000;
+pub const MAX_STANDARD_TX_SIGOPS_COST: usize = 16_000;
+// Version 3 (TRUC) transactions can not be bigger than 10,000vB
+pub const MAX_TRUC_TX_WEIGHT: usize = 40_000;
+// Default minimum relay fee rate of Bitcoin Core (sat/vB)
+pub const MIN_RELAY_FEE_RATE: f32 = 1.0;
+
+// Pay-to-anchor script, OP_1 <0x4e73>, anyone can spend it without a signature
+pub const P2A_SCRIPT: [u8; 4] = [0x51, 0x02, 0x4e, 0x73];
+
+// What the pairs of a group must have in common besides the fee band.
+// SIGHASH_SINGLE|ANYONECANPAY signatures commit to the nLockTime and the version, so all the pairs of
+// a group share them. With denominations the outputs of a group are also of the same amount, and with
+// homogeneous scripts the inputs and outputs are of the same type
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Bucket {
+    pub lock_time: u32,
+    pub version: i32,
+    // Denomination of the outputs in sats, 0 if the group is not denominated
+    pub denomination: u64,
+    pub input_class: ScriptClass,
+    pub output_class: ScriptClass,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum ScriptClass {
+    // Script types are not taken into account
+    Any,
+    // Several script types in the same transaction
+    Mixed,
+    P2pkh,
+    P2sh,
+    P2wpkh,
+    P2wsh,
+    P2tr,
+    Other,
+}
+
+impl ScriptClass {
+    fn of_input(txin: &TxIn) -> Self {
+        // The script spent is not known, so the type is guessed from the scriptSig and witness.
+        // Validation only accepts inputs with a <signature> <pubkey or script> witness, so they are
+        // native or nested segwit v0 spends
+        if !txin.script_sig.is_empty() {
+            // Nested segwit
+            return ScriptClass::P2sh;
+        }
+        if txin.witness.last().map(|pubkey| pubkey.len()) == Some(33) {
+            return ScriptClass::P2wpkh;
+        }
+        return ScriptClass::P2wsh;
+    }
+
+    fn of_output(txout: &TxOut) -> Self {
+        let script = &txout.script_pubkey;
+        if script.is_p2pkh() {
+            return ScriptClass::P2pkh;
+        }
+        if script.is_p2sh() {
+            return ScriptClass::P2sh;
+        }
+        if script.is_v0_p2wpkh() {
+            return ScriptClass::P2wpkh;
+        }
+        if script.is_v0_p2wsh() {
+            return ScriptClass::P2wsh;
+        }
+        if script.is_v1_p2tr() {
+            return ScriptClass::P2tr;
+        }
+        return ScriptClass::Other;
+    }
+
+    fn common<I: Iterator<Item = ScriptClass>>(mut classes: I) -> Self {
+        // Return the class shared by all the scripts, or Mixed
+        let first = match classes.next() {
+            Some(class) => class,
+            None => return ScriptClass::Mixed,
+        };
+        if classes.all(|class| class == first) {
+            return first;
+        }
+        return ScriptClass::Mixed;
+    }
+}
+
+impl Bucket {
+    pub fn of(tx: &Transaction) -> Self {
+        // Only the outputs paired with an input count, the anchor output of a group transaction does not
+        let outputs = &tx.output[..tx.input.len().min(tx.output.len())];
+        let (input_class, output_class) = if crate::CONFIG.group.homogeneous_scripts {
+            (ScriptClass::common(tx.input.iter().map(ScriptClass::of_input)), ScriptClass::common(outputs.iter().map(ScriptClass::of_output)))
+        }
+        else {
+            (ScriptClass::Any, ScriptClass::Any)
+        };
+        Bucket {
+            lock_time: tx.lock_time.to_consensus_u32(),
+            version: tx.version,
+            denomination: denomination(outputs),
+            input_class,
+            output_class,
+        }
+    }
+
+    pub fn max_weight(&self) -> usize {
+        // Return the maximum weight of a group transaction of this bucket
+        if self.version == 3 {
+            return MAX_TRUC_TX_WEIGHT;
+        }
+        return MAX_STANDARD_TX_WEIGHT;
+    }
+}
 
 pub struct Group {
+    pub id: u64,
     pub fee_rate: f32,
+    pub bucket: Bucket,
     pub timestamp: i64,
     transactions: Vec<(TxIn, TxOut)>,
     transaction_group: Transaction,
+    // Txid of the submitted transaction each pair comes from, by input outpoint
+    submissions: HashMap<OutPoint, Txid>,
+    // Fee paid by each submitted transaction
+    fees: HashMap<Txid, u64>,
+    // Client connection each submitted transaction was received from
+    sources: HashMap<Txid, SocketAddr>,
+    // Reason given by the close policy that decided to close the group
+    pub close_reason: Option<String>,
+    // Transactions broadcasted while closing the group
+    pub broadcasts: Vec<Transaction>,
+    // Pairs evicted while closing the group and the reason
+    pub evicted: Vec<(OutPoint, String)>,
+    // Dropped group transaction this group has been rebuilt from
+    pub rebuilt_from: Option<Txid>,
+    // Result of each broadcast channel for the transactions broadcasted while closing the group
+    pub channel_results: HashMap<Txid, (ChannelResults, bool)>,
+    // Since when the group pays less than the estimate for its confirmation target
+    pub below_estimate_since: Option<i64>,
+    // Confirmation target and estimate (sat/vB) of a group that has been under it for too long
+    pub stale: Option<(usize, f32)>,
 
 }
 
 
 impl Group {
-    pub fn new(fee_rate: f32) -> Self {
+    pub fn new(id: u64, fee_rate: f32, bucket: Bucket) -> Self {
         Group {
+            id,
             fee_rate,
+            bucket,
             timestamp: Utc::now().timestamp(),
             transactions: Vec::new(),
-            transaction_group: Transaction {
-                version: 2,
-                lock_time: LockTime::from_height(0).unwrap(),
-                input: Vec::new(),
-                output: Vec::new(),
-            },
+            transaction_group: build_transaction(&[], bucket),
+            submissions: HashMap::new(),
+            fees: HashMap::new(),
+            sources: HashMap::new(),
+            close_reason: None,
+            broadcasts: Vec::new(),
+            evicted: Vec::new(),
+            rebuilt_from: None,
+            channel_results: HashMap::new(),
+            below_estimate_since: None,
+            stale: None,
 
         }
     }
 
+    pub fn from_transaction(id: u64, fee_rate: f32, tx: &Transaction, submissions: &HashMap<OutPoint, Txid>, fees: &HashMap<Txid, u64>) -> Self {
+        // Recreate a group from a group transaction already built. The anchor output, if any, is past
+        // the last input so it is not taken as a pair
+        let mut group = Group::new(id, fee_rate, Bucket::of(tx));
+        group.fees = fees.clone();
+        for (txin, txout) in tx.input.iter().zip(tx.output.iter()) {
+            group.transactions.push((txin.clone(), txout.clone()));
+            if let Some(submission) = submissions.get(&txin.previous_output) {
+                group.submissions.insert(txin.previous_output, *submission);
+            }
+        }
+        group
+    }
+
     pub fn get_num_transactions(&self) -> usize {
         // Return number of transactions in the group
         return self.transactions.len()
     }
 
-    pub fn contains_txin(&self, txin: &TxIn) -> bool {
-        // Return true or false if the given tx input is already in this group
-        self.transactions.iter().any(|(t, _)| t.previous_output == txin.previous_output)
+    pub fn num_submissions(&self) -> usize {
+        // Return the number of different submitted transactions with pairs in the group
+        self.submissions.values().collect::<HashSet<_>>().len()
+    }
+
+    pub fn num_connections(&self) -> usize {
+        // Return the number of different client connections that sent the pairs of the group
+        self.sources.values().collect::<HashSet<_>>().len()
+    }
+
+    pub fn total_fee(&self) -> u64 {
+        // Return the fees paid by the submitted transactions with pairs in the group
+        self.submissions.values()
+            .collect::<HashSet<_>>()
+            .iter()
+            .filter_map(|txid| self.fees.get(*txid))
+            .sum()
+    }
+
+    pub fn fees_of(&self, tx: &Transaction) -> HashMap<Txid, u64> {
+        // Return the fee paid by each submitted transaction with pairs in tx
+        tx.input.iter()
+            .filter_map(|txin| self.submission(&txin.previous_output))
+            .filter_map(|txid| self.fees.get(&txid).map(|fee| (txid, *fee)))
+            .collect()
+    }
+
+    pub fn vsize(&self) -> usize {
+        // Return the size of the group transaction with the current pairs
+        build_transaction(&self.transactions, self.bucket).vsize()
+    }
+
+    pub fn merged_fee_rate(&self, other: &Group) -> f32 {
+        // Return the fee rate (sat/vB) the group transaction would pay after absorbing other
+        let mut pairs = self.transactions.clone();
+        pairs.extend(other.transactions.iter().cloned());
+        return group_fee_rate(self.total_fee() + other.total_fee(), &build_transaction(&pairs, self.bucket));
+    }
+
+    pub fn sigops_cost(&self) -> usize {
+        // Return the sigops cost of the group transaction with the current pairs
+        self.transactions.iter().map(|(txin, txout)| pair_sigops_cost(txin, txout)).sum()
+    }
+
+    pub fn check_fits(&self, tx: &Transaction) -> Result<(), String> {
+        // Check that the group transaction would stay within the standardness limits after adding tx
+        let pairs: Vec<(TxIn, TxOut)> = tx.input.iter().cloned().zip(tx.output.iter().cloned()).collect();
+        return self.check_fits_pairs(&pairs);
+    }
+
+    pub fn check_fits_group(&self, other: &Group) -> Result<(), String> {
+        // Check that the group transaction would stay within the standardness limits after absorbing other
+        return self.check_fits_pairs(&other.transactions);
+    }
+
+    fn check_fits_pairs(&self, new_pairs: &[(TxIn, TxOut)]) -> Result<(), String> {
+        let mut pairs = self.transactions.clone();
+        pairs.extend(new_pairs.iter().cloned());
+
+        let weight = build_transaction(&pairs, self.bucket).weight().to_wu() as usize;
+        if weight > self.bucket.max_weight() {
+            return Err(format!("weight would be {}WU, over the {}WU limit", weight, self.bucket.max_weight()));
+        }
+
+        let sigops = self.sigops_cost() + new_pairs.iter().map(|(txin, txout)| pair_sigops_cost(txin, txout)).sum::<usize>();
+        if sigops > MAX_STANDARD_TX_SIGOPS_COST {
+            return Err(format!("sigops cost would be {}, over the {} limit", sigops, MAX_STANDARD_TX_SIGOPS_COST));
+        }
+
+        return Ok(());
+    }
+
+    pub fn check_quota(&self, tx: &Transaction, source: SocketAddr) -> Result<(), String> {
+        // Check that adding tx would not give a single source more pairs of the group than allowed,
+        // so one participant can not fill the group alone.
+        // Inputs signed with the same public key, or spent together in a submitted transaction,
+        // are taken as coming from the same owner
+        let quota = &crate::CONFIG.quota;
+        let new_pairs = tx.input.len();
+
+        if let Some(limit) = quota.per_submission {
+            if new_pairs > limit {
+                return Err(format!("the transaction has {} pairs, over the limit of {} per transaction", new_pairs, limit));
+            }
+        }
+
+        let pairs_from = |from: &dyn Fn(&Txid) -> bool| -> usize {
+            self.submissions.values().filter(|txid| from(txid)).count()
+        };
+
+        if let Some(limit) = quota.per_connection {
+            let pairs = pairs_from(&|txid| self.sources.get(txid) == Some(&source)) + new_pairs;
+            if pairs > limit {
+                return Err(format!("the group would have {} pairs from this connection, over the limit of {}", pairs, limit));
+            }
+        }
+
+        if let Some(limit) = quota.per_ip {
+            let pairs = pairs_from(&|txid| self.sources.get(txid).map(|s| s.ip()) == Some(source.ip())) + new_pairs;
+            if pairs > limit {
+                return Err(format!("the group would have {} pairs from this IP address, over the limit of {}", pairs, limit));
+            }
+        }
+
+        if let Some(limit) = quota.per_cluster {
+            let keys: HashSet<&[u8]> = tx.input.iter().filter_map(|txin| txin.witness.last()).collect();
+            let cluster: HashSet<Txid> = self.transactions.iter()
+                .filter(|(txin, _)| txin.witness.last().is_some_and(|key| keys.contains(key)))
+                .filter_map(|(txin, _)| self.submission(&txin.previous_output))
+                .collect();
+            let pairs = pairs_from(&|txid| cluster.contains(txid)) + new_pairs;
+            if pairs > limit {
+                return Err(format!("the group would have {} pairs from the same owner, over the limit of {}", pairs, limit));
+            }
+        }
+
+        return Ok(());
+    }
+
+    pub fn outpoints(&self) -> impl Iterator<Item = OutPoint> + '_ {
+        // Return the outpoints spent by the pairs of this group
+        self.transactions.iter().map(|(t, _)| t.previous_output)
+    }
+
+    pub fn submission(&self, outpoint: &OutPoint) -> Option<Txid> {
+        // Return the txid of the submitted transaction that spends the given outpoint
+        self.submissions.get(outpoint).copied()
+    }
+
+    pub fn remove_submission(&mut self, outpoint: &OutPoint) -> Vec<(TxIn, TxOut)> {
+        // Take out of the group every pair of the submitted transaction spending the given outpoint.
+        // Only the whole submission is checked to pay its fee, a single pair can have an output bigger
+        // than its input, so the pairs of a submission always stay or go together
+        let submission = self.submission(outpoint);
+        let mut removed = Vec::new();
+        let mut i = 0;
+        while i != self.transactions.len() {
+            let previous_output = self.transactions[i].0.previous_output;
+            if previous_output == *outpoint || (submission.is_some() && self.submission(&previous_output) == submission) {
+                removed.push(self.transactions.remove(i));
+            }
+            else {
+                i += 1;
+            }
+        }
+
+        self.forget_submission_fee(outpoint);
+        removed
+    }
+
+    fn forget_submission_fee(&mut self, outpoint: &OutPoint) {
+        // Stop counting the fee and the source of the submission spending the outpoint
+        if let Some(txid) = self.submission(outpoint) {
+            self.fees.remove(&txid);
+            self.sources.remove(&txid);
+        }
+    }
+
+    fn split_submissions(&self, pairs: Vec<(TxIn, TxOut)>) -> Vec<Vec<(TxIn, TxOut)>> {
+        // Split the pairs by the submitted transaction they come from, keeping their order
+        let mut submissions: Vec<Vec<(TxIn, TxOut)>> = Vec::new();
+        let mut index: HashMap<Txid, usize> = HashMap::new();
+        for pair in pairs {
+            match self.submission(&pair.0.previous_output) {
+                Some(txid) => {
+                    match index.get(&txid) {
+                        Some(i) => submissions[*i].push(pair),
+                        None => {
+                            index.insert(txid, submissions.len());
+                            submissions.push(vec![pair]);
+                        }
+                    }
+                },
+                None => submissions.push(vec![pair]),
+            }
+        }
+        return submissions;
+    }
+
+    pub fn evict_submission(&mut self, outpoint: &OutPoint, reason: &str) {
+        // Remove the pairs of the submission spending the outpoint from the group and report them as evicted
+        for (txin, _) in self.remove_submission(outpoint) {
+            self.report_eviction(&txin, reason);
+        }
+    }
+
+    pub fn forget_removed_pairs(&mut self) {
+        // Drop the submissions of the pairs that are not in the group anymore
+        let kept: HashSet<OutPoint> = self.outpoints().collect();
+        self.submissions.retain(|outpoint, _| kept.contains(outpoint));
+        let kept_submissions: HashSet<Txid> = self.submissions.values().copied().collect();
+        self.fees.retain(|txid, _| kept_submissions.contains(txid));
+        self.sources.retain(|txid, _| kept_submissions.contains(txid));
+    }
+
+    pub fn contains_submission(&self, txid: &Txid) -> bool {
+        // Return true if some pair of the group comes from the given submitted transaction
+        self.submissions.values().any(|t| t == txid)
     }
     
 
-    pub fn add_tx(&mut self, tx_hex: &str) -> bool {
-        // tx_hex must be a valid transaction for this group (Checks must be done before)
+    pub fn add_tx(&mut self, tx: &Transaction, fee: u64, source: SocketAddr) {
+        // tx must be a valid transaction for this group (Checks must be done before)
         // add the transaction to the group
-        // return true or false depending if the group has been closed after adding the new transaction
-
-        let tx: Transaction = deserialize(&hex_decode(tx_hex).unwrap()).unwrap();
 
+        let txid = tx.txid();
         for i in 0..tx.input.len() {
             self.transactions.push((tx.input[i].clone(), tx.output[i].clone()));
+            self.submissions.insert(tx.input[i].previous_output, txid);
         }
 
-        println!("{}: Tx {} added to group with fee_rate {}sat/vB", Utc::now(), tx.txid(), self.fee_rate);
+        self.fees.insert(txid, fee);
+        self.sources.insert(txid, source);
 
-        // Check if the group should be closed according to the MAX_SIZE limit established in config file
-        if self.transactions.len() >= crate::CONFIG.group.max_size {
-            return self.close_group();
-        }
-        return false;
+        println!("{}: Tx {} added to group {} with fee_rate {}sat/vB", Utc::now(), txid, self.id, self.fee_rate);
     }
 
+    pub fn absorb(&mut self, other: Group) {
+        // Move the pairs of another group of the same fee rate into this one.
+        // The group keeps the oldest timestamp of both so it does not wait longer than it should
+        self.timestamp = self.timestamp.min(other.timestamp);
+        self.transactions.extend(other.transactions);
+        self.submissions.extend(other.submissions);
+        self.fees.extend(other.fees);
+        self.sources.extend(other.sources);
+    }
 
-    fn create_group_transaction(&mut self) {
-        // Creates the final group transaction ready to be broadcasted
+    pub fn submissions(&self) -> HashSet<Txid> {
+        // Return the submitted transactions with pairs in the group
+        self.submissions.values().copied().collect()
+    }
 
-        // Clean inputs in outputs in case there's some data (should not)
-        self.transaction_group.input.clear();
-        self.transaction_group.output.clear();
 
-        // add inputs and outputs
-        for in_out_tuple in &self.transactions {
-            self.transaction_group.input.push(in_out_tuple.0.clone());
-            self.transaction_group.output.push(in_out_tuple.1.clone());
+    fn create_group_transaction(&mut self) {
+        // Creates the final group transaction ready to be broadcasted.
+        // The pairs are shuffled with the OS random number generator so the position of an input does
+        // not tell when it was submitted. Each input keeps its output at the same index
+        self.transactions.shuffle(&mut OsRng);
+        self.transaction_group = build_transaction(&self.transactions, self.bucket);
+
+        // The anchor is paid from the fees of the participants. If they can not pay it on top of the
+        // minimum relay fee the group transaction is built without it, otherwise it would never be relayed
+        let vsize = self.transaction_group.vsize() as f32;
+        if has_anchor(&self.transaction_group) && (self.total_fee() as f32) < anchor_value() as f32 + MIN_RELAY_FEE_RATE * vsize {
+            eprintln!("{}: Group {} ({}sat/vb) can not pay the anchor output, building its transaction without it", Utc::now(), self.id, self.fee_rate);
+            self.transaction_group = build_pairs_transaction(&self.transactions, self.bucket);
         }
     }
     
 
     pub fn close_group(&mut self) -> bool {
         // Finalize the transaction and send it to the network
+        // The group must be detached from the registry before calling this, it does network calls
+        // and must not be run while holding the GLOBAL_GROUPS lock
     
-        // Connect to Electrum node
-        let config = ConfigBuilder::new().validate_domain(crate::CONFIG.electrum.certificate_validation).build();
-        let client = Client::from_config(&crate::CONFIG.electrum.endpoint, config.clone()).unwrap();
+        // Connect to Electrum node, or to some broadcast channel if it is down
+        let client = match channels::connect_any() {
+            Some(client) => client,
+            None => return false,
+        };
         let blockchain = ElectrumBlockchain::from(client);
         
         // Check that the transactions included in the group have not been already spent
@@ -105,15 +487,15 @@ impl Group {
                 Ok(Some(tx)) => {
                     // validate if the output has been spent
                     let utxo_script_pubkey = &tx.output[outpoint.vout as usize].script_pubkey;
-                    let utxo_list = blockchain.script_list_unspent(&utxo_script_pubkey);
+                    let utxo_list = blockchain.script_list_unspent(utxo_script_pubkey);
                     match utxo_list {
                         Ok(returned_utxo_list) => {
-                            if returned_utxo_list.len() > 0 {
+                            if !returned_utxo_list.is_empty() {
                                 i += 1;
                             }
                             else {
                                 eprintln!("{}: Double spending detected on a group, deleting that transaction...", Utc::now());
-                                self.transactions.remove(i);
+                                self.evict_submission(&outpoint, "Input already spent");
                                 return false;
                             }
                         },
@@ -137,28 +519,206 @@ impl Group {
 
         // Create the group transaction
         self.create_group_transaction();
-        
+
         let tx_hex = serialize_hex(&self.transaction_group);
         println!("{}: Group transaction: \n", Utc::now());
         println!("{}: {:?}", Utc::now(), tx_hex);
 
-        let tx_bytes = hex_decode(tx_hex).unwrap();
-
         // broadcast the transaction
-        // There's a issue with client 1 here... TODO FIX
-        let client2 = Client::from_config(&crate::CONFIG.electrum.endpoint, config.clone()).unwrap();
-        let txid = client2.transaction_broadcast_raw(&tx_bytes);
-
-        match txid {
+        match self.broadcast(&self.transaction_group.clone()) {
             Ok(id) => {
-                println!("{}: Group {}sat/vb closed! Transaction broadcasted with TXID: {}", Utc::now(), self.fee_rate, id);
+                println!("{}: Group {} ({}sat/vb) closed ({})! Transaction broadcasted with TXID: {}", Utc::now(), self.id, self.fee_rate, self.close_reason.as_deref().unwrap_or("no reason"), id);
+                self.broadcasts.push(self.transaction_group.clone());
                 return true;
             },
-            Err(e) => {
-                eprintln!("{}: There is an error broadcasting the transaction group: {:?}", Utc::now(), e);
+            Err(Some(reason)) => {
+                // The transaction was rejected, some pair is invalid or conflicts with the mempool
+                eprintln!("{}: The transaction group {} was rejected: {}", Utc::now(), self.id, reason);
+                return self.evict_offending_pairs();
+            },
+            Err(None) => {
                 return false;
             }
-    
         }
     }
-}
\ No newline at end of file
+
+    fn evict_offending_pairs(&mut self) -> bool {
+        // Find the submissions that make the group transaction be rejected, evict them and broadcast the rest.
+        // Only the whole submission is checked to pay its fee, so their pairs are tested together: a node
+        // supporting testmempoolaccept checks each submission alone. Otherwise the group is bisected by
+        // submission and the halves that are accepted are broadcasted.
+        // Returns true if no pair is left to broadcast. Pairs that could not be sent stay in the group.
+
+        let submissions = self.split_submissions(self.transactions.clone());
+        let submission_txs: Vec<Transaction> = submissions.iter()
+            .map(|pairs| build_pairs_transaction(pairs, self.bucket))
+            .collect();
+
+        if let Some(results) = test_mempool_accept(&submission_txs) {
+            let mut num_evicted = 0;
+            for (pairs, result) in submissions.iter().zip(results.iter()) {
+                if let Some(reason) = result {
+                    self.evict_submission(&pairs[0].0.previous_output, reason);
+                    num_evicted += 1;
+                }
+            }
+
+            if num_evicted == 0 {
+                eprintln!("{}: All submissions of group {} are valid alone, keeping the group", Utc::now(), self.id);
+                return false;
+            }
+            if self.transactions.is_empty() {
+                return true;
+            }
+
+            self.create_group_transaction();
+            return match self.broadcast(&self.transaction_group.clone()) {
+                Ok(id) => {
+                    println!("{}: Group {} ({}sat/vb) closed without the evicted pairs! Transaction broadcasted with TXID: {}", Utc::now(), self.id, self.fee_rate, id);
+                    self.broadcasts.push(self.transaction_group.clone());
+                    true
+                },
+                Err(_) => false,
+            };
+        }
+
+        self.transactions.clear();
+        let mut unsent: Vec<(TxIn, TxOut)> = Vec::new();
+        self.broadcast_bisecting(submissions, &mut unsent);
+        self.transactions = unsent;
+
+        return self.transactions.is_empty();
+    }
+
+    fn broadcast_bisecting(&mut self, submissions: Vec<Vec<(TxIn, TxOut)>>, unsent: &mut Vec<(TxIn, TxOut)>) {
+        // Broadcast the submissions as a single transaction. If it is rejected split them in two halves and try again
+        // with each one until the rejected submissions are isolated and evicted.
+        // Pairs that could not be sent for other reasons (e.g. connection errors) are pushed to unsent.
+        // The pairs of each submission are next to each other here, so they are shuffled like in the group transaction
+        let mut pairs: Vec<(TxIn, TxOut)> = submissions.iter().flatten().cloned().collect();
+        pairs.shuffle(&mut OsRng);
+        let tx = build_pairs_transaction(&pairs, self.bucket);
+
+        match self.broadcast(&tx) {
+            Ok(id) => {
+                println!("{}: Part of group {} ({}sat/vb) with {} pairs broadcasted with TXID: {}", Utc::now(), self.id, self.fee_rate, pairs.len(), id);
+                self.broadcasts.push(tx);
+            },
+            Err(Some(reason)) => {
+                if submissions.len() == 1 {
+                    for (txin, _) in pairs.iter() {
+                        self.report_eviction(txin, &reason);
+                    }
+                    self.forget_submission_fee(&pairs[0].0.previous_output);
+                    return;
+                }
+                let mut first_half = submissions;
+                let second_half = first_half.split_off(first_half.len() / 2);
+                self.broadcast_bisecting(first_half, unsent);
+                self.broadcast_bisecting(second_half, unsent);
+            },
+            Err(None) => {
+                unsent.extend(pairs);
+            }
+        }
+    }
+
+    fn broadcast(&mut self, tx: &Transaction) -> Result<Txid, Option<String>> {
+        // Broadcast a transaction through the Electrum server and the other channels, keeping the result of each one
+        // and whether some server has it in its mempool.
+        // On error returns the reason if the transaction was rejected, or None if it was not sent
+
+        // The pairs pay the band fee rate for their own vbytes, the overhead of the transaction has to be
+        // covered by what they pay over it. If it is not, the group moves to the band the transaction
+        // really pays and the transaction is not broadcasted below the band it was in
+        let fee_rate = group_fee_rate(self.fees_of(tx).values().sum(), tx);
+        if fee_rate < self.fee_rate {
+            let band = bands::band_for(fee_rate);
+            eprintln!("{}: Group {} transaction pays {}sat/vB, under its {}sat/vB band, moving it to the {}sat/vB band", Utc::now(), self.id, fee_rate, self.fee_rate, band);
+            self.fee_rate = band;
+            return Err(None);
+        }
+
+        let (result, channel_results) = channels::broadcast(tx);
+        let seen = result.as_ref().map(|accepted| accepted.seen).unwrap_or(false);
+        self.channel_results.insert(tx.txid(), (channel_results, seen));
+        result.map(|accepted| accepted.txid)
+    }
+
+    fn report_eviction(&mut self, txin: &TxIn, reason: &str) {
+        eprintln!("{}: Pair spending {} evicted from group {} ({}sat/vb): {}", Utc::now(), txin.previous_output, self.id, self.fee_rate, reason);
+        self.evicted.push((txin.previous_output, reason.to_string()));
+    }
+}
+
+fn group_fee_rate(total_fee: u64, group_tx: &Transaction) -> f32 {
+    // Return the fee rate (sat/vB) a group transaction pays from the fees of its participants,
+    // without the part that goes to the anchor output if it has one
+    let anchor = if has_anchor(group_tx) { anchor_value() } else { 0 };
+    return total_fee.saturating_sub(anchor) as f32 / group_tx.vsize() as f32;
+}
+
+fn has_anchor(group_tx: &Transaction) -> bool {
+    // Every input has its output at the same index, so only the anchor goes after them
+    return group_tx.output.len() > group_tx.input.len();
+}
+
+fn denomination(outputs: &[TxOut]) -> u64 {
+    // Return the denomination all the outputs belong to, or 0 if they do not share one
+    let conf = &crate::CONFIG.denomination;
+    let denomination_of = |value: u64| -> u64 {
+        match conf.mode {
+            DenominationMode::Off => 0,
+            DenominationMode::Exact => conf.values.iter().copied().find(|d| *d == value).unwrap_or(0),
+            DenominationMode::Range => conf.values.iter().copied().filter(|d| *d <= value).max().unwrap_or(0),
+        }
+    };
+
+    let first = match outputs.first() {
+        Some(output) => denomination_of(output.value),
+        None => return 0,
+    };
+    if outputs.iter().all(|output| denomination_of(output.value) == first) {
+        return first;
+    }
+    return 0;
+}
+
+pub fn anchor_output() -> Option<TxOut> {
+    // Return the pay-to-anchor output added to the group transactions, if enabled
+    if !crate::CONFIG.group.anchor {
+        return None;
+    }
+    Some(TxOut {
+        value: crate::CONFIG.group.anchor_value,
+        script_pubkey: ScriptBuf::from_bytes(P2A_SCRIPT.to_vec()),
+    })
+}
+
+pub fn anchor_value() -> u64 {
+    anchor_output().map(|anchor| anchor.value).unwrap_or(0)
+}
+
+fn build_transaction(pairs: &[(TxIn, TxOut)], bucket: Bucket) -> Transaction {
+    // Build a group transaction with the given pairs and the anchor output if enabled.
+    // SIGHASH_SINGLE only commits to the output at the index of its input, so the anchor output can
+    // go after the last pair
+    let mut tx = build_pairs_transaction(pairs, bucket);
+    if let Some(anchor) = anchor_output() {
+        tx.output.push(anchor);
+    }
+    tx
+}
+
+fn build_pairs_transaction(pairs: &[(TxIn, TxOut)], bucket: Bucket) -> Transaction {
+    // Build a transaction with only the given pairs, keeping each input and output at the same index.
+    // Used when looking for the pairs a group transaction is rejected for, where the anchor value
+    // could make a pair alone pay too little
+    Transaction {
+        version: bucket.version,
+        lock_time: LockTime::from_consensus(bucket.lock_time),
+        input: pairs.iter().map(|(txin, _)| txin.clone()).collect(),
+        output: pairs.iter().map(|(_, txout)| txout.clone()).collect(),
+    }
+}
+
@@ -1 +1,8 @@
-pub mod group;
\ No newline at end of file
+pub mod bands;
+pub mod blocks;
+pub mod channels;
+pub mod estimates;
+pub mod group;
+pub mod policy;
+pub mod registry;
+pub mod tracker;
\ No newline at end of file
@@ -0,0 +1,189 @@
+//! Policies deciding when a group has to be closed.
+//! Every policy looks at a group and, if it must be closed, returns a description of the reason,
+//! which is recorded with the close. Policies can be combined with AnyOf and AllOf.
+
+use std::collections::HashMap;
+
+use crate::server::bands;
+use crate::server::group::Group;
+
+// Information about the moment the policies are checked
+pub struct CloseContext {
+    pub now: i64,
+    // Fee rate (sat/vB) needed to confirm within each number of blocks, if known
+    pub fee_estimates: HashMap<usize, f32>,
+    // A new block has been found since the last check
+    pub new_block: bool,
+}
+
+pub trait ClosePolicy: Send + Sync {
+    // Return the reason why the group must be closed, or None if it can stay open
+    fn check(&self, group: &Group, ctx: &CloseContext) -> Option<String>;
+}
+
+// Close when the group has the number of pairs set for its band
+pub struct PairCount;
+
+impl ClosePolicy for PairCount {
+    fn check(&self, group: &Group, _ctx: &CloseContext) -> Option<String> {
+        let max_size = bands::max_size(group.fee_rate);
+        if group.get_num_transactions() >= max_size {
+            return Some(format!("pair count reached {}", max_size));
+        }
+        None
+    }
+}
+
+// Close when the group transaction is this big
+pub struct TotalVbytes(pub usize);
+
+impl ClosePolicy for TotalVbytes {
+    fn check(&self, group: &Group, _ctx: &CloseContext) -> Option<String> {
+        if group.vsize() >= self.0 {
+            return Some(format!("size reached {}vB", self.0));
+        }
+        None
+    }
+}
+
+// Close when the fees paid by the group add up to this amount of sats
+pub struct TotalFee(pub u64);
+
+impl ClosePolicy for TotalFee {
+    fn check(&self, group: &Group, _ctx: &CloseContext) -> Option<String> {
+        if group.total_fee() >= self.0 {
+            return Some(format!("total fee reached {}sat", self.0));
+        }
+        None
+    }
+}
+
+// Close when the group has been open for the number of seconds set for its band
+pub struct Age;
+
+impl ClosePolicy for Age {
+    fn check(&self, group: &Group, ctx: &CloseContext) -> Option<String> {
+        let max_time = bands::max_time(group.fee_rate);
+        if group.timestamp + max_time <= ctx.now {
+            return Some(format!("age reached {}s", max_time));
+        }
+        None
+    }
+}
+
+// Close when the group pays more than the estimate for the confirmation target of its band by this
+// safety margin (sat/vB)
+pub struct FeeMargin(pub f32);
+
+impl ClosePolicy for FeeMargin {
+    fn check(&self, group: &Group, ctx: &CloseContext) -> Option<String> {
+        let target = bands::confirmation_target(group.fee_rate);
+        let estimate = *ctx.fee_estimates.get(&target)?;
+        if estimate < group.fee_rate - self.0 {
+            return Some(format!("fee rate {}sat/vB over the {} blocks estimate {}sat/vB by more than {}sat/vB", group.fee_rate, target, estimate, self.0));
+        }
+        None
+    }
+}
+
+// Close when a new block is found, the mempool has just been cleared
+pub struct NewBlock;
+
+impl ClosePolicy for NewBlock {
+    fn check(&self, _group: &Group, ctx: &CloseContext) -> Option<String> {
+        if ctx.new_block {
+            return Some(String::from("new block"));
+        }
+        None
+    }
+}
+
+// Satisfied when the group has pairs from this number of different submitted transactions
+pub struct MinSubmitters(pub usize);
+
+impl ClosePolicy for MinSubmitters {
+    fn check(&self, group: &Group, _ctx: &CloseContext) -> Option<String> {
+        if group.num_submissions() >= self.0 {
+            return Some(format!("{} submitters", group.num_submissions()));
+        }
+        None
+    }
+}
+
+// Satisfied when the group has pairs sent from this number of different client connections
+pub struct MinConnections(pub usize);
+
+impl ClosePolicy for MinConnections {
+    fn check(&self, group: &Group, _ctx: &CloseContext) -> Option<String> {
+        if group.num_connections() >= self.0 {
+            return Some(format!("{} connections", group.num_connections()));
+        }
+        None
+    }
+}
+
+// Satisfied when any of the policies is, the first one gives the reason
+pub struct AnyOf(pub Vec<Box<dyn ClosePolicy>>);
+
+impl ClosePolicy for AnyOf {
+    fn check(&self, group: &Group, ctx: &CloseContext) -> Option<String> {
+        self.0.iter().find_map(|policy| policy.check(group, ctx))
+    }
+}
+
+// Satisfied when all the policies are, the reasons are joined
+pub struct AllOf(pub Vec<Box<dyn ClosePolicy>>);
+
+impl ClosePolicy for AllOf {
+    fn check(&self, group: &Group, ctx: &CloseContext) -> Option<String> {
+        let mut reasons: Vec<String> = Vec::new();
+        for policy in self.0.iter() {
+            reasons.push(policy.check(group, ctx)?);
+        }
+        Some(reasons.join(" and "))
+    }
+}
+
+pub fn from_config() -> Box<dyn ClosePolicy> {
+    // Build the policies enabled in the config file.
+    // A group is closed when any of the enabled policies says so, as long as it has the minimum number
+    // of submitters and connections
+    let close_conf = &crate::CONFIG.close;
+
+    let mut triggers: Vec<Box<dyn ClosePolicy>> = vec![
+        Box::new(PairCount),
+        Box::new(Age),
+    ];
+    if let Some(max_vbytes) = close_conf.max_vbytes {
+        triggers.push(Box::new(TotalVbytes(max_vbytes)));
+    }
+    if let Some(max_fee) = close_conf.max_fee {
+        triggers.push(Box::new(TotalFee(max_fee)));
+    }
+    if let Some(fee_margin) = close_conf.fee_margin {
+        triggers.push(Box::new(FeeMargin(fee_margin)));
+    }
+    if close_conf.new_block {
+        triggers.push(Box::new(NewBlock));
+    }
+
+    let mut minimums: Vec<Box<dyn ClosePolicy>> = Vec::new();
+    if close_conf.min_submitters > 1 {
+        minimums.push(Box::new(MinSubmitters(close_conf.min_submitters)));
+    }
+    if close_conf.min_connections > 1 {
+        minimums.push(Box::new(MinConnections(close_conf.min_connections)));
+    }
+
+    if !minimums.is_empty() {
+        minimums.push(Box::new(AnyOf(triggers)));
+        return Box::new(AllOf(minimums));
+    }
+    Box::new(AnyOf(triggers))
+}
+
+pub fn meets_minimums(group: &Group) -> bool {
+    // Return true if the group has enough participants to be closed
+    let close_conf = &crate::CONFIG.close;
+    group.num_submissions() >= close_conf.min_submitters && group.num_connections() >= close_conf.min_connections
+}
@@ -0,0 +1,549 @@
+//! Registry holding the open groups together with an index of every outpoint they reserve.
+//! Both are only modified under the same lock so a conflicting input can never land in two groups.
+//!
+//! Closing a group needs network round trips, so groups are first detached from the registry
+//! into a closing state and closed without holding the lock. Their outpoints stay reserved until
+//! the close finishes.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+use bdk::bitcoin::{OutPoint, Transaction, Txid};
+use chrono::Utc;
+
+use crate::config::{Assignment, BelowMinimum};
+use crate::server::bands;
+use crate::server::group::{Bucket, Group};
+use crate::server::policy::{self, CloseContext, ClosePolicy};
+use crate::server::tracker::{Tracker, TxState};
+
+// Where a submitted transaction is
+pub enum SubmissionStatus {
+    // Waiting in an open group
+    Pending(u64, f32),
+    // Its group is being closed
+    Closing(u64),
+    // Part of a broadcasted group transaction
+    Broadcasted(u64, Txid, TxState),
+    // Evicted from its group
+    Evicted(String),
+    // Released from its group because it did not get enough participants
+    NotBatched(String),
+    Unknown,
+}
+
+pub struct Registry {
+    pub groups: Vec<Group>,
+    reserved: HashSet<OutPoint>,
+    // Outpoints reserved by each group being closed, by group id, with the submission they come from
+    closing: HashMap<u64, HashMap<OutPoint, Option<Txid>>>,
+    next_id: u64,
+    // Last group used by the round robin assignment of each fee rate and bucket
+    round_robin: HashMap<(u32, Bucket), usize>,
+    pub tracker: Tracker,
+    policy: Box<dyn ClosePolicy>,
+}
+
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry {
+            groups: Vec::new(),
+            reserved: HashSet::new(),
+            closing: HashMap::new(),
+            next_id: 0,
+            round_robin: HashMap::new(),
+            tracker: Tracker::default(),
+            policy: policy::from_config(),
+        }
+    }
+
+    pub fn is_reserved(&self, outpoint: &OutPoint) -> bool {
+        // Return true if the outpoint is already used by a pair of some group, open or closing
+        self.reserved.contains(outpoint)
+    }
+
+    pub fn num_closing(&self) -> usize {
+        // Return the number of groups that are being closed right now
+        self.closing.len()
+    }
+
+    pub fn add_tx(&mut self, tx: &Transaction, fee_rate: f32, fee: u64, source: SocketAddr) -> Result<Vec<Group>, String> {
+        // Reserve the transaction inputs and add it to the group with the given fee rate.
+        // Everything happens under the caller's lock, so either all the inputs are reserved and the
+        // tx is in a group, or nothing changed.
+        // Groups that must be closed after adding the transaction are detached and returned so the
+        // caller can close them
+
+        let mut seen: HashSet<OutPoint> = HashSet::new();
+        for txin in tx.input.iter() {
+            if self.is_reserved(&txin.previous_output) || !seen.insert(txin.previous_output) {
+                eprintln!("{}: Transaction was rejected, Error: transaction input is already in a group\n", Utc::now());
+                return Err(String::from("Transaction input is already in a group"));
+            }
+        }
+
+        // A transaction that does not fit even in an empty group can never be batched
+        let bucket = Bucket::of(tx);
+        let mut new_group = Group::new(self.next_id, fee_rate, bucket);
+        if let Err(e) = new_group.check_fits(tx) {
+            eprintln!("{}: Transaction was rejected, Error: the group transaction {}\n", Utc::now(), e);
+            return Err(format!("Transaction too big to be batched, the group transaction {}", e));
+        }
+
+        // The transaction is rejected if its source would go over its quota in a new group or in any
+        // group of the band, even if the transaction would end up in another group of the band
+        let band: Vec<usize> = self.band_groups(fee_rate, bucket);
+        let quota = new_group.check_quota(tx, source)
+            .and_then(|_| band.iter().try_for_each(|i| self.groups[*i].check_quota(tx, source)));
+        if let Err(e) = quota {
+            eprintln!("{}: Transaction was rejected, Error: {}\n", Utc::now(), e);
+            return Err(format!("Too many pairs from the same participant, {}", e));
+        }
+        self.reserved.extend(seen);
+
+        let mut detached: Vec<Group> = Vec::new();
+
+        // Search for the groups corresponing to the transaction fee rate where the transaction fits
+        let fitting: Vec<usize> = band.iter().copied().filter(|i| self.groups[*i].check_fits(tx).is_ok()).collect();
+
+        let max_per_band = crate::CONFIG.group.max_per_band.max(1);
+        let chosen = match crate::CONFIG.group.assignment {
+            // Fill the oldest group first, a new one is only opened when the tx does not fit in any
+            Assignment::Oldest => fitting.first().copied(),
+            // Spread the transactions among all the groups the band can have
+            Assignment::RoundRobin => {
+                if band.len() < max_per_band || fitting.is_empty() {
+                    None
+                }
+                else {
+                    let turn = self.round_robin.entry((fee_rate.to_bits(), bucket)).or_insert(0);
+                    *turn += 1;
+                    Some(fitting[*turn % fitting.len()])
+                }
+            },
+        };
+
+        if let Some(i) = chosen {
+            // If some then the group already exist so we add the tx to that group
+            self.groups[i].add_tx(tx, fee, source);
+            return Ok(self.detach_by_policy(&add_tx_context()));
+        }
+
+        if band.len() >= max_per_band {
+            // All the groups of the band are full, close the oldest now and start a sibling group in the same band for this tx
+            let i = band[0];
+            let reason = self.groups[i].check_fits(tx).err().unwrap_or_default();
+            let mut full_group = self.groups.remove(i);
+            full_group.close_reason = Some(format!("standardness limit, the group transaction {}", reason));
+            println!("{}: Closing group {}: {}", Utc::now(), full_group.id, full_group.close_reason.as_deref().unwrap_or_default());
+            self.mark_closing(&full_group);
+            detached.push(full_group);
+        }
+
+        // Otherwise there is room for a new group for this fee rate so we create one
+        self.next_id += 1;
+        println!("{}: New group {} created with fee_rate {}sat/vB", Utc::now(), new_group.id, new_group.fee_rate);
+        new_group.add_tx(tx, fee, source);
+        self.groups.push(new_group);
+
+        detached.extend(self.detach_by_policy(&add_tx_context()));
+        Ok(detached)
+    }
+
+    fn band_groups(&self, fee_rate: f32, bucket: Bucket) -> Vec<usize> {
+        // Return the positions of the open groups with the given fee rate and bucket, from the oldest to the newest
+        let mut band: Vec<usize> = self.groups.iter()
+            .enumerate()
+            .filter(|(_, g)| g.fee_rate == fee_rate && g.bucket == bucket)
+            .map(|(i, _)| i)
+            .collect();
+        band.sort_by_key(|i| (self.groups[*i].timestamp, self.groups[*i].id));
+        band
+    }
+
+    pub fn rebalance_bands(&mut self) {
+        // Move the open groups to the band their fee rate belongs to after the boundaries changed.
+        // A group only moves to a band with the same or a lower fee rate, so its pairs never pay less
+        // than the band asks. A group under every new band keeps its own lower bound instead of falling
+        // to the 0sat/vB band. Groups over the limit of a band are merged into its oldest ones when they fit
+        for group in self.groups.iter_mut() {
+            let band = bands::band_for(group.fee_rate);
+            if band != group.fee_rate && band > 0.0 {
+                println!("{}: Group {} moved from the {}sat/vB band to the {}sat/vB band", Utc::now(), group.id, group.fee_rate, band);
+                group.fee_rate = band;
+            }
+        }
+
+        let max_per_band = crate::CONFIG.group.max_per_band.max(1);
+        let mut keys: Vec<(f32, Bucket)> = self.groups.iter().map(|g| (g.fee_rate, g.bucket)).collect();
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        keys.dedup();
+        for (fee_rate, bucket) in keys {
+            let mut band = self.band_groups(fee_rate, bucket);
+            while band.len() > max_per_band {
+                // Merge the newest group into the oldest one it fits in
+                let newest = band[band.len() - 1];
+                let target = match band[..band.len() - 1].iter().find(|i| self.groups[**i].check_fits_group(&self.groups[newest]).is_ok()) {
+                    Some(i) => *i,
+                    None => break,
+                };
+                let group = self.groups.remove(newest);
+                let target = if target > newest { target - 1 } else { target };
+                println!("{}: Group {} merged into group {}", Utc::now(), group.id, self.groups[target].id);
+                self.groups[target].absorb(group);
+                band = self.band_groups(fee_rate, bucket);
+            }
+        }
+    }
+
+    pub fn update_stale(&mut self, estimates: &HashMap<usize, f32>, now: i64) {
+        // Remember since when each open group pays less than the estimate for its confirmation target,
+        // warning once it has been like that for too long
+        for group in self.groups.iter_mut() {
+            let target = bands::confirmation_target(group.fee_rate);
+            let estimate = match estimates.get(&target) {
+                Some(estimate) => *estimate,
+                None => continue,
+            };
+
+            if group.fee_rate >= estimate {
+                group.below_estimate_since = None;
+                group.stale = None;
+                continue;
+            }
+            let since = *group.below_estimate_since.get_or_insert(now);
+            if since + crate::CONFIG.close.stale_after <= now {
+                if group.stale.is_none() {
+                    eprintln!("{}: Group {} is stale, its fee rate {}sat/vB is under the {} blocks estimate {}sat/vB since {}", Utc::now(), group.id, group.fee_rate, target, estimate, since);
+                }
+                group.stale = Some((target, estimate));
+            }
+        }
+    }
+
+    pub fn merge_underfilled(&mut self, now: i64) {
+        // Before groups are closed by time, merge the under-filled ones with an under-filled group of
+        // the same bucket in the closest band with open groups. Groups without the minimum number of
+        // participants are under-filled too if they have to be merged.
+        // The merged group takes the lower fee rate of both, so the pairs of the higher band may pay
+        // more than their band but never less.
+        // Merging only happens if the merged transaction still pays the lower fee rate
+        let merge_minimums = crate::CONFIG.close.below_minimum == BelowMinimum::Merge;
+        if crate::CONFIG.close.merge_below.is_none() && !merge_minimums {
+            return;
+        }
+        let merge_below = crate::CONFIG.close.merge_below.unwrap_or(0);
+        let underfilled = |group: &Group| group.get_num_transactions() < merge_below || (merge_minimums && !policy::meets_minimums(group));
+
+        let mut i = 0;
+        while i < self.groups.len() {
+            let group = &self.groups[i];
+            if !underfilled(group) || group.timestamp + bands::max_time(group.fee_rate) > now {
+                i += 1;
+                continue;
+            }
+
+            let closest = self.groups.iter()
+                .enumerate()
+                .filter(|(_, g)| g.fee_rate != group.fee_rate && g.bucket == group.bucket && underfilled(g))
+                .min_by(|(_, a), (_, b)| (a.fee_rate - group.fee_rate).abs().total_cmp(&(b.fee_rate - group.fee_rate).abs()))
+                .map(|(j, _)| j);
+            let j = match closest {
+                Some(j) => j,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            let (low, high) = if self.groups[i].fee_rate < self.groups[j].fee_rate { (i, j) } else { (j, i) };
+            if self.groups[low].check_fits_group(&self.groups[high]).is_err() || self.groups[low].merged_fee_rate(&self.groups[high]) < self.groups[low].fee_rate {
+                i += 1;
+                continue;
+            }
+
+            let high_group = self.groups.remove(high);
+            let low = if low > high { low - 1 } else { low };
+            println!("{}: Under-filled group {} ({}sat/vB) merged into group {} ({}sat/vB)", Utc::now(), high_group.id, high_group.fee_rate, self.groups[low].id, self.groups[low].fee_rate);
+            self.groups[low].absorb(high_group);
+
+            // The positions changed, start again. Every merge removes a group so this ends
+            i = 0;
+        }
+    }
+
+    pub fn release_below_minimum(&mut self, now: i64) {
+        // Give up on the groups that did not get the minimum number of participants release_after
+        // seconds after max_time. Their inputs are freed and the submitters can see they were not batched
+        if crate::CONFIG.close.below_minimum != BelowMinimum::Release {
+            return;
+        }
+
+        let mut i = 0;
+        while i < self.groups.len() {
+            let group = &self.groups[i];
+            if policy::meets_minimums(group) || group.timestamp + bands::max_time(group.fee_rate) + crate::CONFIG.close.release_after > now {
+                i += 1;
+                continue;
+            }
+
+            let group = self.groups.remove(i);
+            println!("{}: Releasing group {} without enough participants, {} submissions from {} connections", Utc::now(), group.id, group.num_submissions(), group.num_connections());
+            for outpoint in group.outpoints() {
+                self.reserved.remove(&outpoint);
+            }
+            let reason = format!("only {} submissions from {} connections joined the group", group.num_submissions(), group.num_connections());
+            for submission in group.submissions() {
+                self.tracker.record_not_batched(submission, reason.clone());
+            }
+        }
+    }
+
+    pub fn detach_by_policy(&mut self, ctx: &CloseContext) -> Vec<Group> {
+        // Detach every open group the close policy says must be closed, recording the reason
+        let mut reasons: HashMap<u64, String> = self.groups.iter()
+            .filter_map(|group| self.policy.check(group, ctx).map(|reason| (group.id, reason)))
+            .collect();
+
+        let mut detached = self.detach(|group| reasons.contains_key(&group.id));
+        for group in detached.iter_mut() {
+            println!("{}: Closing group {}: {}", Utc::now(), group.id, reasons[&group.id]);
+            group.close_reason = reasons.remove(&group.id);
+        }
+        detached
+    }
+
+    pub fn detach<F>(&mut self, mut should_close: F) -> Vec<Group>
+    where
+        F: FnMut(&Group) -> bool,
+    {
+        // Move every open group matching the condition to the closing state and return them.
+        // Their outpoints stay reserved until finish_closing is called
+        let mut detached: Vec<Group> = Vec::new();
+
+        let mut i = 0;
+        while i < self.groups.len() {
+            if should_close(&self.groups[i]) {
+                let group = self.groups.remove(i);
+                self.mark_closing(&group);
+                detached.push(group);
+            }
+            else {
+                i += 1;
+            }
+        }
+
+        detached
+    }
+
+    fn mark_closing(&mut self, group: &Group) {
+        // Remember the outpoints of a detached group, they stay reserved while it is being closed
+        self.closing.insert(group.id, group.outpoints().map(|o| (o, group.submission(&o))).collect());
+    }
+
+    pub fn finish_closing(&mut self, mut group: Group, closed: bool) {
+        // Called once a detached group has been closed, successfully or not.
+        // Broadcasted transactions start being tracked and evicted pairs are recorded.
+        // A closed group frees all its outpoints. Otherwise only the pairs dropped while closing
+        // are freed and the group goes back to the open list.
+        let reserved = self.closing.remove(&group.id).unwrap_or_default();
+
+        for tx in std::mem::take(&mut group.broadcasts) {
+            let submissions: HashMap<OutPoint, Txid> = tx.input.iter()
+                .filter_map(|txin| group.submission(&txin.previous_output).map(|t| (txin.previous_output, t)))
+                .collect();
+            if let Some(dropped_txid) = group.rebuilt_from {
+                self.tracker.mark_rebuilt(&dropped_txid, tx.txid());
+            }
+            let fees = group.fees_of(&tx);
+            let txid = tx.txid();
+            self.tracker.track(group.id, group.fee_rate, tx, submissions, fees, group.close_reason.clone());
+            if let Some((channels, seen)) = group.channel_results.remove(&txid) {
+                self.tracker.record_channels(&txid, channels);
+                if !seen {
+                    self.tracker.mark_unconfirmed(&txid);
+                }
+            }
+        }
+        for (outpoint, reason) in std::mem::take(&mut group.evicted) {
+            if let Some(txid) = group.submission(&outpoint) {
+                self.tracker.record_eviction(txid, reason);
+            }
+        }
+
+        if closed {
+            for outpoint in reserved.keys() {
+                self.reserved.remove(outpoint);
+            }
+            return;
+        }
+
+        group.forget_removed_pairs();
+        let kept: HashSet<OutPoint> = group.outpoints().collect();
+        for outpoint in reserved.keys().filter(|o| !kept.contains(o)) {
+            self.reserved.remove(outpoint);
+        }
+
+        if group.get_num_transactions() == 0 {
+            return;
+        }
+
+        // New groups may have been opened for the same fee rate while this one was closing.
+        // If the band has no room left the pairs are moved to its oldest group they fit in,
+        // or kept as their own group if they would push every group over the standardness limits
+        let band = self.band_groups(group.fee_rate, group.bucket);
+        if band.len() < crate::CONFIG.group.max_per_band.max(1) {
+            self.groups.push(group);
+            return;
+        }
+        match band.iter().copied().find(|i| self.groups[*i].check_fits_group(&group).is_ok()) {
+            Some(i) => self.groups[i].absorb(group),
+            None => self.groups.push(group),
+        }
+    }
+
+    pub fn rebuild_dropped(&mut self, txid: &Txid, conflicting: &[OutPoint]) -> Option<Group> {
+        // Rebuild the group of a dropped group transaction without the submissions with some input
+        // double spent. Every pair is signed with SIGHASH_SINGLE|ANYONECANPAY so the rest are still valid.
+        // The new group is returned in the closing state, ready to be closed by the caller.
+        // It gets a new id, the old one may still be used by the rest of the group or another rebuild
+        let tracked = self.tracker.get(txid)?;
+        let dropped_group_id = tracked.group_id;
+        let mut group = Group::from_transaction(self.next_id, tracked.fee_rate, &tracked.tx, &tracked.submissions, &tracked.fees);
+        self.next_id += 1;
+        // The dropped transaction has been dealt with, whether some pairs are left or not
+        self.tracker.finish(txid);
+        group.rebuilt_from = Some(*txid);
+        group.close_reason = Some(String::from("rebuilt after a double spend"));
+
+        for outpoint in conflicting {
+            group.evict_submission(outpoint, "Input double spent after the group was broadcasted");
+        }
+        for (outpoint, reason) in std::mem::take(&mut group.evicted) {
+            if let Some(submission) = group.submission(&outpoint) {
+                self.tracker.record_eviction(submission, reason);
+            }
+        }
+
+        // Participants may have already sent their transaction again to a new group
+        let already_reserved: Vec<OutPoint> = group.outpoints().filter(|o| self.is_reserved(o)).collect();
+        for outpoint in already_reserved.iter() {
+            group.remove_submission(outpoint);
+        }
+        group.forget_removed_pairs();
+
+        if group.get_num_transactions() == 0 {
+            return None;
+        }
+
+        println!("{}: Rebuilding group {} as group {} without the double spent pairs, {} pairs left", Utc::now(), dropped_group_id, group.id, group.get_num_transactions());
+        self.reserved.extend(group.outpoints());
+        self.mark_closing(&group);
+        Some(group)
+    }
+
+    pub fn submission_status(&self, txid: &Txid) -> SubmissionStatus {
+        // Find where a submitted transaction is, from the most to the least recent state
+        if let Some(group) = self.groups.iter().find(|g| g.contains_submission(txid)) {
+            return SubmissionStatus::Pending(group.id, group.fee_rate);
+        }
+        for (group_id, submissions) in self.closing.iter() {
+            if submissions.values().any(|t| t.as_ref() == Some(txid)) {
+                return SubmissionStatus::Closing(*group_id);
+            }
+        }
+        if let Some(tracked) = self.tracker.find_submission(txid) {
+            return SubmissionStatus::Broadcasted(tracked.group_id, tracked.txid, tracked.state.clone());
+        }
+        if let Some(reason) = self.tracker.eviction(txid) {
+            return SubmissionStatus::Evicted(reason.clone());
+        }
+        if let Some(reason) = self.tracker.not_batched(txid) {
+            return SubmissionStatus::NotBatched(reason.clone());
+        }
+        SubmissionStatus::Unknown
+    }
+}
+
+fn add_tx_context() -> CloseContext {
+    // Policies checked when a transaction is added only look at the group itself
+    CloseContext {
+        now: Utc::now().timestamp(),
+        fee_estimates: HashMap::new(),
+        new_block: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bdk::bitcoin::{ScriptBuf, Sequence, TxIn, TxOut, Witness, absolute::LockTime, hashes::Hash};
+
+    fn outpoint(n: u8) -> OutPoint {
+        OutPoint::new(Txid::hash(&[n]), 0)
+    }
+
+    fn transaction(outpoints: &[OutPoint]) -> Transaction {
+        // A P2WPKH transaction with one pair for each outpoint
+        let mut p2wpkh = vec![0x00, 0x14];
+        p2wpkh.extend([1u8; 20]);
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: outpoints.iter().map(|o| TxIn {
+                previous_output: *o,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[vec![0x83; 72], vec![2u8; 33]]),
+            }).collect(),
+            output: outpoints.iter().map(|_| TxOut { value: 10_000, script_pubkey: ScriptBuf::from_bytes(p2wpkh.clone()) }).collect(),
+        }
+    }
+
+    fn source() -> SocketAddr {
+        "127.0.0.1:10000".parse().unwrap()
+    }
+
+    #[test]
+    fn add_tx_rejects_an_input_already_reserved() {
+        let mut registry = Registry::new();
+        assert!(registry.add_tx(&transaction(&[outpoint(1)]), 1.0, 200, source()).is_ok());
+
+        // The second input is already in a group, the first one must not stay reserved
+        assert!(registry.add_tx(&transaction(&[outpoint(2), outpoint(1)]), 1.0, 400, source()).is_err());
+        assert!(registry.is_reserved(&outpoint(1)));
+        assert!(!registry.is_reserved(&outpoint(2)));
+        assert_eq!(registry.groups.iter().map(|g| g.get_num_transactions()).sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn add_tx_rejects_an_input_repeated_in_the_transaction() {
+        let mut registry = Registry::new();
+        assert!(registry.add_tx(&transaction(&[outpoint(3), outpoint(3)]), 1.0, 400, source()).is_err());
+        assert!(!registry.is_reserved(&outpoint(3)));
+        assert!(registry.groups.is_empty());
+    }
+
+    #[test]
+    fn finish_closing_a_group_not_closed_frees_only_the_dropped_pairs() {
+        let mut registry = Registry::new();
+        assert!(registry.add_tx(&transaction(&[outpoint(4)]), 1.0, 200, source()).is_ok());
+        assert!(registry.add_tx(&transaction(&[outpoint(5)]), 1.0, 200, source()).is_ok());
+
+        let mut detached = registry.detach(|_| true);
+        assert_eq!(detached.len(), 1);
+        let mut group = detached.remove(0);
+        assert_eq!(registry.num_closing(), 1);
+
+        // The pair spending outpoint 4 is dropped while closing and the group can not be closed
+        group.remove_submission(&outpoint(4));
+        registry.finish_closing(group, false);
+
+        assert!(!registry.is_reserved(&outpoint(4)));
+        assert!(registry.is_reserved(&outpoint(5)));
+        assert_eq!(registry.num_closing(), 0);
+        assert_eq!(registry.groups.len(), 1);
+        assert_eq!(registry.groups[0].get_num_transactions(), 1);
+    }
+}
@@ -0,0 +1,241 @@
+//! Follow the group transactions once they are broadcasted, until they are buried deep enough.
+//! Transactions dropped from the mempool are broadcasted again, and reorgs are detected by
+//! comparing the confirmation height with the one seen before.
+
+use std::collections::HashMap;
+
+use bdk::bitcoin::{OutPoint, Transaction, Txid};
+use bdk::electrum_client::{Client, ElectrumApi, Error as ElectrumError};
+
+use crate::server::channels::{self, ChannelResults};
+use chrono::Utc;
+
+// Number of finished group transactions kept for the history
+const MAX_HISTORY: usize = 1000;
+
+#[derive(Clone, PartialEq)]
+pub enum TxState {
+    // Waiting in the mempool
+    Mempool,
+    // Accepted by some server but not seen in any mempool yet
+    Unconfirmed,
+    // Included in a block at the given height
+    Confirmed(u32),
+    // Not in the mempool anymore and the rebroadcast was rejected
+    Dropped(String),
+    // Dropped because some participant double spent its input, rebuilt without it
+    Rebuilt(Txid),
+}
+
+// What the Electrum server reports about a group transaction
+pub enum Observed {
+    Mempool,
+    Confirmed(u32),
+    Missing,
+}
+
+pub struct TrackedTx {
+    pub group_id: u64,
+    pub fee_rate: f32,
+    pub txid: Txid,
+    pub tx: Transaction,
+    pub broadcast_at: i64,
+    // Why the group was closed
+    pub close_reason: Option<String>,
+    pub state: TxState,
+    pub rebroadcasts: u32,
+    // Txid of the submitted transaction each input comes from
+    pub submissions: HashMap<OutPoint, Txid>,
+    // Fee paid by each submitted transaction
+    pub fees: HashMap<Txid, u64>,
+    // Result of the last broadcast through each channel
+    pub channels: ChannelResults,
+    // Set once the transaction has enough confirmations, it is not watched anymore
+    pub finished: bool,
+}
+
+#[derive(Default)]
+pub struct Tracker {
+    txs: Vec<TrackedTx>,
+    // Submitted transactions evicted from their group and the reason
+    evicted: HashMap<Txid, String>,
+    // Submitted transactions released from a group without enough participants and the reason
+    not_batched: HashMap<Txid, String>,
+}
+
+
+impl Tracker {
+    pub fn track(&mut self, group_id: u64, fee_rate: f32, tx: Transaction, submissions: HashMap<OutPoint, Txid>, fees: HashMap<Txid, u64>, close_reason: Option<String>) {
+        // Start watching a group transaction that has just been broadcasted
+        self.txs.push(TrackedTx {
+            group_id,
+            fee_rate,
+            txid: tx.txid(),
+            tx,
+            broadcast_at: Utc::now().timestamp(),
+            close_reason,
+            state: TxState::Mempool,
+            rebroadcasts: 0,
+            submissions,
+            fees,
+            channels: Vec::new(),
+            finished: false,
+        });
+        self.prune();
+    }
+
+    pub fn record_channels(&mut self, txid: &Txid, channels: ChannelResults) {
+        if let Some(tracked) = self.txs.iter_mut().find(|t| t.txid == *txid) {
+            tracked.channels = channels;
+        }
+    }
+
+    pub fn finish(&mut self, txid: &Txid) {
+        // Stop watching a group transaction
+        if let Some(tracked) = self.txs.iter_mut().find(|t| t.txid == *txid) {
+            tracked.finished = true;
+        }
+    }
+
+    pub fn mark_unconfirmed(&mut self, txid: &Txid) {
+        // The transaction was accepted but no server had it in its mempool afterwards
+        if let Some(tracked) = self.txs.iter_mut().find(|t| t.txid == *txid) {
+            tracked.state = TxState::Unconfirmed;
+        }
+    }
+
+    pub fn record_eviction(&mut self, submission: Txid, reason: String) {
+        self.evicted.insert(submission, reason);
+    }
+
+    pub fn eviction(&self, submission: &Txid) -> Option<&String> {
+        // Return the reason why a submitted transaction was evicted from its group, if it was
+        self.evicted.get(submission)
+    }
+
+    pub fn record_not_batched(&mut self, submission: Txid, reason: String) {
+        self.not_batched.insert(submission, reason);
+    }
+
+    pub fn not_batched(&self, submission: &Txid) -> Option<&String> {
+        // Return the reason why a submitted transaction was released without being batched, if it was
+        self.not_batched.get(submission)
+    }
+
+    pub fn find_submission(&self, submission: &Txid) -> Option<&TrackedTx> {
+        // Return the latest group transaction that includes the given submitted transaction
+        self.txs.iter().rev().find(|t| t.submissions.values().any(|t| t == submission))
+    }
+
+    pub fn get(&self, txid: &Txid) -> Option<&TrackedTx> {
+        self.txs.iter().find(|t| t.txid == *txid)
+    }
+
+    pub fn mark_rebuilt(&mut self, txid: &Txid, new_txid: Txid) {
+        // Link a dropped group transaction with the one rebuilt from it
+        if let Some(tracked) = self.txs.iter_mut().find(|t| t.txid == *txid) {
+            tracked.state = TxState::Rebuilt(new_txid);
+        }
+    }
+
+    pub fn history(&self) -> &[TrackedTx] {
+        &self.txs
+    }
+
+    pub fn watched(&self) -> Vec<(Txid, Transaction)> {
+        // Return the group transactions that still have to be checked
+        self.txs.iter()
+            .filter(|t| !t.finished)
+            .map(|t| (t.txid, t.tx.clone()))
+            .collect()
+    }
+
+    pub fn update(&mut self, txid: &Txid, observed: Observed, tip: u32) {
+        // Apply what the Electrum server reported about a group transaction
+
+        let tracked = match self.txs.iter_mut().find(|t| t.txid == *txid) {
+            Some(tracked) => tracked,
+            None => return,
+        };
+
+        match observed {
+            Observed::Confirmed(height) => {
+                if let TxState::Confirmed(previous) = tracked.state {
+                    if previous != height {
+                        eprintln!("{}: Reorg detected, group transaction {} moved from block {} to block {}", Utc::now(), txid, previous, height);
+                    }
+                }
+                else {
+                    println!("{}: Group transaction {} confirmed at height {}", Utc::now(), txid, height);
+                }
+                tracked.state = TxState::Confirmed(height);
+
+                // Stop watching it once it is buried deep enough
+                if tip + 1 >= height + crate::CONFIG.tracker.confirmations {
+                    tracked.finished = true;
+                }
+            },
+            Observed::Mempool => {
+                if let TxState::Confirmed(previous) = tracked.state {
+                    eprintln!("{}: Reorg detected, group transaction {} confirmed at height {} is back in the mempool", Utc::now(), txid, previous);
+                }
+                tracked.state = TxState::Mempool;
+            },
+            Observed::Missing => {
+                // Missing transactions are broadcasted again, the result is applied by rebroadcast_result
+            },
+        }
+    }
+
+    pub fn rebroadcast_result(&mut self, txid: &Txid, result: Result<bool, String>) {
+        // Record the result of broadcasting again a group transaction that dropped from the mempool
+        let tracked = match self.txs.iter_mut().find(|t| t.txid == *txid) {
+            Some(tracked) => tracked,
+            None => return,
+        };
+
+        match result {
+            Ok(seen) => {
+                tracked.rebroadcasts += 1;
+                tracked.state = if seen { TxState::Mempool } else { TxState::Unconfirmed };
+            },
+            Err(reason) => {
+                // It is still watched until its group has been rebuilt or found not to need it
+                eprintln!("{}: Group transaction {} dropped from the mempool and can not be broadcasted again: {}", Utc::now(), txid, reason);
+                tracked.state = TxState::Dropped(reason);
+            }
+        }
+    }
+
+    fn prune(&mut self) {
+        // Forget the oldest finished transactions once the history is full
+        while self.txs.len() > MAX_HISTORY {
+            match self.txs.iter().position(|t| t.finished) {
+                Some(i) => { self.txs.remove(i); },
+                None => break,
+            }
+        }
+    }
+}
+
+pub fn observe(client: &Client, tx: &Transaction) -> Result<Observed, ElectrumError> {
+    // Ask the Electrum server where a group transaction is.
+    // Electrum indexes by script, so the history of the first output tells if the transaction
+    // is confirmed (height > 0), in the mempool (height <= 0) or unknown
+    let txid = tx.txid();
+    let history = client.script_get_history(&tx.output[0].script_pubkey)?;
+
+    match history.iter().find(|h| h.tx_hash == txid) {
+        Some(entry) if entry.height > 0 => Ok(Observed::Confirmed(entry.height as u32)),
+        Some(_) => Ok(Observed::Mempool),
+        None => Ok(Observed::Missing),
+    }
+}
+
+pub fn rebroadcast(tx: &Transaction) -> (Result<bool, Option<String>>, ChannelResults) {
+    // Broadcast again a group transaction through every channel.
+    // Returns whether some server has it in its mempool afterwards. On error returns the reason if the
+    // transaction was rejected, or None if no server could be reached
+    let (result, channels) = channels::broadcast(tx);
+    (result.map(|accepted| accepted.seen), channels)
+}
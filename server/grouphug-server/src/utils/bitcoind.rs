@@ -0,0 +1,47 @@
+//! Optional connection to a Bitcoin Core node, used for the checks the Electrum protocol can not do.
+
+use bdk::bitcoin::{Transaction, consensus::encode::serialize_hex};
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use chrono::Utc;
+
+pub fn connect() -> Option<Client> {
+    // Return a RPC client if a bitcoind node is configured
+    let bitcoind = crate::CONFIG.bitcoind.as_ref()?;
+    let auth = Auth::UserPass(bitcoind.user.clone(), bitcoind.password.clone());
+    match Client::new(&bitcoind.url, auth) {
+        Ok(client) => Some(client),
+        Err(e) => {
+            eprintln!("{}: Could not connect to bitcoind: {}", Utc::now(), e);
+            None
+        }
+    }
+}
+
+pub fn test_mempool_accept(txs: &[Transaction]) -> Option<Vec<Option<String>>> {
+    // Check each transaction against the node mempool without broadcasting it.
+    // Returns, for every transaction, None if it would be accepted or the reject reason otherwise.
+    // Returns None if there is no bitcoind configured or the node could not answer.
+    let client = connect()?;
+
+    // Transactions are tested one by one, as a package a rejected one would make the others fail too
+    let mut results: Vec<Option<String>> = Vec::new();
+    for tx in txs {
+        match client.test_mempool_accept(&[serialize_hex(tx)]) {
+            Ok(result) if !result.is_empty() => {
+                if result[0].allowed {
+                    results.push(None);
+                }
+                else {
+                    results.push(Some(result[0].reject_reason.clone().unwrap_or_default()));
+                }
+            },
+            Ok(_) => return None,
+            Err(e) => {
+                eprintln!("{}: testmempoolaccept failed: {}", Utc::now(), e);
+                return None;
+            }
+        }
+    }
+
+    Some(results)
+}
@@ -1 +1,2 @@
-pub mod transactions;
\ No newline at end of file
+pub mod transactions;
+pub mod bitcoind;
\ No newline at end of file
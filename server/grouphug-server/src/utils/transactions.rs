@@ -1,10 +1,16 @@
 //! Functions related to the transactions validation and manipulation.
+// The functions of this module end with an explicit return
+#![allow(clippy::needless_return)]
 use chrono::Utc;
 use bdk::bitcoin::{
     OutPoint,
+    Script,
     Transaction,
-    consensus::encode::deserialize,
-    blockdata::locktime::absolute::{Height, Time}};
+    TxIn,
+    TxOut,
+    consensus::encode::{deserialize, serialize},
+    blockdata::opcodes::all::{OP_CHECKMULTISIG, OP_CHECKMULTISIGVERIFY, OP_CHECKSIG, OP_CHECKSIGVERIFY, OP_PUSHNUM_1, OP_PUSHNUM_16},
+    blockdata::script::Instruction};
 
 use bdk::blockchain::{ElectrumBlockchain, GetTx};
 use bdk::electrum_client::{Client, ConfigBuilder, ElectrumApi};
@@ -34,7 +40,7 @@ pub fn which_network(tx: &Transaction) -> bool {
     return false;
 }
 
-pub fn get_previous_utxo_value(utxo: OutPoint) -> f32 {
+pub fn get_previous_utxo_value(utxo: OutPoint) -> u64 {
     // Given an input from a certain transaction returns the value of the pointed UTXO.
     // If no UTXO is recieved back, the value returned is 0.
 
@@ -47,16 +53,16 @@ pub fn get_previous_utxo_value(utxo: OutPoint) -> f32 {
 
     match tx_result {
         Ok(Some(tx)) => {
-            return tx.output[utxo.vout as usize].value as f32;
+            return tx.output[utxo.vout as usize].value;
         },
         Ok(None) => {
             eprintln!("{}: Previous transaction query returned NONE", Utc::now());
-            return 0.0;
+            return 0;
         }
         Err(erro) => {
             eprintln!("{}: There is an error retrieving previous transaction", Utc::now());
             eprintln!("{}: {}", Utc::now(), erro);
-            return 0.0;
+            return 0;
         }
 
     }
@@ -80,11 +86,11 @@ pub fn previous_utxo_spent(tx: &Transaction) -> bool {
     
                 // validate if the output has been spent
                 let utxo_script_pubkey = &tx.output[outpoint.vout as usize].script_pubkey;
-                let utxo_list = blockchain.script_list_unspent(&utxo_script_pubkey);
+                let utxo_list = blockchain.script_list_unspent(utxo_script_pubkey);
     
                 match utxo_list {
                     Ok(returned_utxo_list) => {
-                        if returned_utxo_list.len() == 0 {
+                        if returned_utxo_list.is_empty() {
                             eprintln!("{}: Transaction already spent", Utc::now());
                             return false;
                         }
@@ -109,11 +115,115 @@ pub fn previous_utxo_spent(tx: &Transaction) -> bool {
     return true;
 }
 
-pub fn check_absolute_locktime(tx: &Transaction) -> bool {
-    // Return true or false depending if the absolute locktime is 0.
-    let height_expected = Height::from_consensus(0).unwrap();
-    let time_expected = Time::MIN;
-    return tx.is_absolute_timelock_satisfied(height_expected, time_expected);
+pub fn spent_outpoints(client: &Client, tx: &Transaction) -> Result<Vec<OutPoint>, String> {
+    // Return the outpoints spent by the transaction inputs that are now spent by another transaction,
+    // either confirmed or in the mempool. Inputs spent by the transaction itself are not conflicting,
+    // it may just be missing from the index of the server
+    let txid = tx.txid();
+    let mut spent: Vec<OutPoint> = Vec::new();
+
+    for txin in tx.input.iter() {
+        let outpoint = txin.previous_output;
+        let previous_tx = client.transaction_get(&outpoint.txid).map_err(|e| format!("{:?}", e))?;
+        let script_pubkey = &previous_tx.output[outpoint.vout as usize].script_pubkey;
+        let unspent = client.script_list_unspent(script_pubkey).map_err(|e| format!("{:?}", e))?;
+        if unspent.iter().any(|u| u.tx_hash == outpoint.txid && u.tx_pos == outpoint.vout as usize) {
+            continue;
+        }
+
+        // Look for the transaction spending it in the history of the script
+        let history = client.script_get_history(script_pubkey).map_err(|e| format!("{:?}", e))?;
+        for entry in history.iter() {
+            if entry.tx_hash == txid || entry.tx_hash == outpoint.txid {
+                continue;
+            }
+            let spending_tx = client.transaction_get(&entry.tx_hash).map_err(|e| format!("{:?}", e))?;
+            if spending_tx.input.iter().any(|i| i.previous_output == outpoint) {
+                spent.push(outpoint);
+                break;
+            }
+        }
+    }
+
+    return Ok(spent);
+}
+
+fn count_sigops(script: &Script, accurate: bool) -> usize {
+    // Count the signature operations of a script the same way Bitcoin Core does.
+    // Multisig counts as 20 unless accurate is set and the number of keys is right before it
+    let mut sigops = 0;
+    let mut last_opcode = None;
+
+    for instruction in script.instructions() {
+        let opcode = match instruction {
+            Ok(Instruction::Op(opcode)) => opcode,
+            Ok(Instruction::PushBytes(_)) => {
+                last_opcode = None;
+                continue;
+            },
+            Err(_) => break,
+        };
+
+        if opcode == OP_CHECKSIG || opcode == OP_CHECKSIGVERIFY {
+            sigops += 1;
+        }
+        else if opcode == OP_CHECKMULTISIG || opcode == OP_CHECKMULTISIGVERIFY {
+            sigops += match last_opcode {
+                Some(n) if accurate && n >= OP_PUSHNUM_1.to_u8() && n <= OP_PUSHNUM_16.to_u8() => (n - OP_PUSHNUM_1.to_u8() + 1) as usize,
+                _ => 20,
+            };
+        }
+        last_opcode = Some(opcode.to_u8());
+    }
+    return sigops;
+}
+
+pub fn pair_sigops_cost(txin: &TxIn, txout: &TxOut) -> usize {
+    // Return the sigops cost a pair adds to the group transaction.
+    // Legacy sigops of the scriptSig and the output count 4 times.
+    // The prevout script is not known here, so the witness type is guessed from the witness itself:
+    // <signature> <33 bytes pubkey> is P2WPKH (1 sigop), a single element is a taproot key path spend (0 sigops)
+    // and otherwise the last element is taken as the witness script
+    let mut cost = 4 * (count_sigops(&txin.script_sig, false) + count_sigops(&txout.script_pubkey, false));
+
+    if txin.witness.len() == 2 && txin.witness.last().map(|pubkey| pubkey.len()) == Some(33) {
+        cost += 1;
+    }
+    else if txin.witness.len() > 1 {
+        if let Some(witness_script) = txin.witness.last() {
+            cost += count_sigops(Script::from_bytes(witness_script), true);
+        }
+    }
+    return cost;
+}
+
+pub fn pairs_vsize(tx: &Transaction) -> f32 {
+    // Return the vbytes the pairs of a transaction add to a group transaction.
+    // The version, locktime and input/output counts are paid once by the group transaction,
+    // so only the inputs (with their witness) and the outputs are counted
+    let weight: usize = tx.input.iter()
+        .zip(tx.output.iter())
+        .map(|(txin, txout)| {
+            let witness = if txin.witness.is_empty() { 0 } else { txin.witness.serialized_len() };
+            4 * serialize(txin).len() + witness + 4 * serialize(txout).len()
+        })
+        .sum();
+    return weight as f32 / 4.0;
+}
+
+pub fn check_absolute_locktime(tx: &Transaction, tip: Option<u32>) -> bool {
+    // Return true or false depending if the absolute locktime is 0 or a height at or below the current tip.
+    // Wallets doing anti fee sniping set it to the current height. Locktimes by time are not accepted
+    let tip = match tip {
+        Some(tip) => tip,
+        None => return tx.lock_time.to_consensus_u32() == 0,
+    };
+    if !tx.lock_time.is_block_height() {
+        return false;
+    }
+    // A locktime equal to the tip is already final for the next block. It is checked even if the
+    // inputs do not enable it, the inputs of other participants in the group may do
+    return tx.lock_time.to_consensus_u32() <= tip;
 }
 
 pub fn check_dust_limit(tx: &Transaction) -> bool {
@@ -128,8 +238,50 @@ pub fn check_dust_limit(tx: &Transaction) -> bool {
 }
 
 pub fn check_tx_version(tx: &Transaction) -> bool {
-    // Return ture or false if the tx version is 2
-    return tx.version == 2;
+    // Return ture or false if the tx version is 2, or 3 (TRUC) when enabled in the config file
+    return tx.version == 2 || (tx.version == 3 && crate::CONFIG.group.truc);
+}
+
+pub fn previous_txs_confirmed(tx: &Transaction) -> bool {
+    // Validates that the transactions spent by the inputs are confirmed.
+    // A TRUC transaction can only have one unconfirmed parent, a group spending outputs of
+    // unconfirmed transactions of several participants would break that limit
+
+    // Connect to Electrum node
+    let config = ConfigBuilder::new().validate_domain(crate::CONFIG.electrum.certificate_validation).build();
+    let client = match Client::from_config(&crate::CONFIG.electrum.endpoint, config) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{}: Could not connect to the Electrum server: {:?}", Utc::now(), e);
+            return false;
+        }
+    };
+
+    for txin in tx.input.iter() {
+        let outpoint = txin.previous_output;
+        let previous_tx = match client.transaction_get(&outpoint.txid) {
+            Ok(previous_tx) => previous_tx,
+            Err(_e) => {
+                eprintln!("{}: Could not retrieve previous transaction", Utc::now());
+                return false;
+            }
+        };
+
+        // Electrum reports mempool transactions with a height of 0 or less
+        match client.script_get_history(&previous_tx.output[outpoint.vout as usize].script_pubkey) {
+            Ok(history) => {
+                if !history.iter().any(|h| h.tx_hash == outpoint.txid && h.height > 0) {
+                    return false;
+                }
+            },
+            Err(_e) => {
+                eprintln!("{}: Error querying for the previous transaction history", Utc::now());
+                return false;
+            }
+        }
+    }
+
+    return true;
 }
 
 pub fn get_num_inputs_and_outputs(tx: &Transaction) -> (usize, usize) {
@@ -150,7 +302,7 @@ pub fn check_sighash_single_anyone_can_pay(tx: &Transaction) -> bool {
         match input_query.last() {
             Some(input) => {
                 // 131 decimal representation of 0x83 designated to SIGHASH_SINGLE | ANYONECANPAY
-                if *input != 131 as u8{
+                if *input != 131 {
                     return false;
                 }
             },
@@ -163,98 +315,191 @@ pub fn check_sighash_single_anyone_can_pay(tx: &Transaction) -> bool {
     return true;
 }
 
-pub fn validate_tx_query_one_to_one_single_anyone_can_pay(tx_hex: &str ) -> (bool, String, f32) {
+pub fn validate_tx_query_one_to_one_single_anyone_can_pay(tx_hex: &str ) -> (bool, String, u64) {
     // Validate that a given transaction (in hex) is valid according to the rules.
-    // Returns true if the tx is valid. String with the error message if any and a f32 with the fee_rate of the transaction    
+    // Returns true if the tx is valid. String with the error message if any and a u64 with the exact fee of the transaction    
     // Rules:
     //  - Must have same number of inputs and outputs.
     //  - The input cannot be spent before must be and UTXO.
     //  - Signature must be SIGHASH_SINGLE | ANYONECANPAY.
-    //  - Must have absolute locktime to 0.
+    //  - Absolute locktime must be 0 or a height at or below the tip.
     //  - Fee rate must be bigger than 1.01sat/vb
     
     
-    let mut real_fee_rate: f32 = 0.0;
+    let mut real_fee: u64 = 0;
     
     let tx_hex_decoded = match hex_decode(tx_hex) {
         Ok(decoded) => decoded,
-        Err(_) => return (false, String::from("Error decoding hex"), real_fee_rate),
+        Err(_) => return (false, String::from("Error decoding hex"), real_fee),
     };
     let tx: Transaction = match deserialize(&tx_hex_decoded) {
         Ok(transaction) => transaction,
-        Err(_) => return (false, String::from("Error deserializing transaction"), real_fee_rate),
+        Err(_) => return (false, String::from("Error deserializing transaction"), real_fee),
     };
     
     // Check that the transaction belongs to the specified network
     let network: bool = which_network(&tx);
     if !network {
         let msg = format!("The tx you provided is not from {} network", &crate::CONFIG.network.name);
-        return (false, msg, real_fee_rate);
+        return (false, msg, real_fee);
     }
     
     // Check that the number of inputs and outputs is the same
     let num_inputs_and_outputs: (usize, usize) = get_num_inputs_and_outputs(&tx);
     if  num_inputs_and_outputs.0 != num_inputs_and_outputs.1 {
         let msg = format!("Number of inputs and outputs must be equal. Inputs = {} | Outputs = {}", num_inputs_and_outputs.0, num_inputs_and_outputs.1);
-        return (false, msg, real_fee_rate);
+        return (false, msg, real_fee);
     }
     
-    // Check that the absolute lock time is disabled or set to 0
-    let abs_lock_time: bool = check_absolute_locktime(&tx);
+    // Check that the absolute lock time is disabled, set to 0 or to a block already found
+    let abs_lock_time: bool = check_absolute_locktime(&tx, crate::server::blocks::tip());
     if !abs_lock_time {
-        let msg = String::from("Absolute locktime is not 0");
-        return (false,msg, real_fee_rate);
+        let msg = String::from("Absolute locktime is not 0 or a height at or below the current tip");
+        return (false,msg, real_fee);
     }
 
     // Check that the transaction value is over the dust limit specified in the config file
     let dust_limit_valid: bool = check_dust_limit(&tx);
     if !dust_limit_valid {
         let msg = format!("The transaction value is under the dust limit {}", &crate::CONFIG.dust.limit);
-        return (false,msg, real_fee_rate);
+        return (false,msg, real_fee);
     }
 
-    // Check that the transaction version is v2
+    // Check that the transaction version is v2, or v3 if TRUC groups are enabled
     let tx_version_correct: bool = check_tx_version(&tx);
     if !tx_version_correct{
-        let msg = String::from("Tx version is not 2");
-        return (false, msg, real_fee_rate);
+        let msg = if crate::CONFIG.group.truc { String::from("Tx version is not 2 or 3") } else { String::from("Tx version is not 2") };
+        return (false, msg, real_fee);
+    }
+
+    // TRUC groups can only spend confirmed outputs
+    if tx.version == 3 && !previous_txs_confirmed(&tx) {
+        let msg = String::from("Version 3 transactions must spend confirmed outputs");
+        return (false, msg, real_fee);
     }
 
     
     // Check that the previous utxos values are not 0
     // Aka there is no OP_RETURN
-    let mut total_fee: f32 = 0.0;
+    // The fee is summed in sats from the exact values, a single pair may pay a negative fee
+    let mut total_fee: i64 = 0;
     for i in 0..tx.input.len() {
-        let previous_utxo_value: f32 = get_previous_utxo_value(tx.input[i].previous_output);
-        if previous_utxo_value == 0.0 {
+        let previous_utxo_value: u64 = get_previous_utxo_value(tx.input[i].previous_output);
+        if previous_utxo_value == 0 {
             let msg = String::from("There's an error loading the previous utxo value");
-            return (false,msg, real_fee_rate);
+            return (false,msg, real_fee);
         } else{
-            total_fee += previous_utxo_value - tx.output[i].value as f32;   
+            total_fee += previous_utxo_value as i64 - tx.output[i].value as i64;
         }
     }
-    real_fee_rate = total_fee/tx.vsize() as f32;
+    real_fee = total_fee.max(0) as u64;
+    // The minimum is checked on the whole transaction, so the pairs also pay their share of the
+    // group transaction overhead. The band is chosen later from the fee rate of the pairs alone
+    let real_fee_rate = total_fee as f32 / tx.vsize() as f32;
 
     // Check that the fee rate is not under 1sat/vb
     if real_fee_rate <= 1.01 {
         let msg = format!("Fee bellow 1 sat/vB. Fee rate found {}sat/vB", real_fee_rate);
-        return (false,msg, real_fee_rate);
+        return (false,msg, real_fee);
     }
     
 
     // Check that the signature type is SIGHASH_SINGLE |ANYONECANPAY
     if !check_sighash_single_anyone_can_pay(&tx) {
         let msg = String::from("Wrong sighash used");
-        return (false,msg, real_fee_rate);
+        return (false,msg, real_fee);
     }
 
     // Check if there's a double spending attempt
     if !previous_utxo_spent(&tx) {
         let msg = String::from("Double spending detected");
-        return (false,msg, real_fee_rate);
+        return (false,msg, real_fee);
     }
 
     
-    return (true, String::from("Ok"), real_fee_rate);
+    return (true, String::from("Ok"), real_fee);
+
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bdk::bitcoin::{ScriptBuf, Sequence, Witness, absolute::LockTime, blockdata::opcodes::all::{OP_DUP, OP_EQUALVERIFY, OP_HASH160, OP_PUSHBYTES_0}, script::Builder};
+
+    fn p2wpkh_script() -> ScriptBuf {
+        return Builder::new().push_opcode(OP_PUSHBYTES_0).push_slice([1u8; 20]).into_script();
+    }
+
+    fn p2pkh_script() -> ScriptBuf {
+        return Builder::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_slice([1u8; 20])
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+    }
+
+    fn multisig_script() -> ScriptBuf {
+        // 2 of 3 multisig
+        return Builder::new()
+            .push_int(2)
+            .push_slice([2u8; 33])
+            .push_slice([2u8; 33])
+            .push_slice([2u8; 33])
+            .push_int(3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+    }
 
-}
\ No newline at end of file
+    fn pair(witness: Witness, script_pubkey: ScriptBuf) -> (TxIn, TxOut) {
+        let txin = TxIn { previous_output: OutPoint::null(), script_sig: ScriptBuf::new(), sequence: Sequence::MAX, witness };
+        let txout = TxOut { value: 10_000, script_pubkey };
+        return (txin, txout);
+    }
+
+    fn p2wpkh_witness() -> Witness {
+        return Witness::from_slice(&[vec![3u8; 72], vec![2u8; 33]]);
+    }
+
+    #[test]
+    fn count_sigops_of_known_scripts() {
+        assert_eq!(count_sigops(&p2wpkh_script(), false), 0);
+        assert_eq!(count_sigops(&p2pkh_script(), false), 1);
+        assert_eq!(count_sigops(&multisig_script(), false), 20);
+        assert_eq!(count_sigops(&multisig_script(), true), 3);
+    }
+
+    #[test]
+    fn pair_sigops_cost_by_script_type() {
+        // P2WPKH spend to a P2WPKH output: one witness sigop
+        let (txin, txout) = pair(p2wpkh_witness(), p2wpkh_script());
+        assert_eq!(pair_sigops_cost(&txin, &txout), 1);
+
+        // Legacy sigops of the output count 4 times
+        let (txin, txout) = pair(p2wpkh_witness(), p2pkh_script());
+        assert_eq!(pair_sigops_cost(&txin, &txout), 5);
+
+        // Taproot key path spend
+        let (txin, txout) = pair(Witness::from_slice(&[vec![3u8; 64]]), p2wpkh_script());
+        assert_eq!(pair_sigops_cost(&txin, &txout), 0);
+
+        // P2WSH 2 of 3 multisig counts the keys of the witness script
+        let witness = Witness::from_slice(&[vec![], vec![3u8; 72], vec![3u8; 72], multisig_script().into_bytes()]);
+        let (txin, txout) = pair(witness, p2wpkh_script());
+        assert_eq!(pair_sigops_cost(&txin, &txout), 3);
+    }
+
+    #[test]
+    fn pairs_vsize_leaves_out_the_transaction_overhead() {
+        // A P2WPKH input with a 72 bytes signature is 68vB and a P2WPKH output 31vB
+        let (txin, txout) = pair(p2wpkh_witness(), p2wpkh_script());
+        let tx = Transaction { version: 2, lock_time: LockTime::ZERO, input: vec![txin.clone()], output: vec![txout.clone()] };
+        assert_eq!(pairs_vsize(&tx), 99.0);
+        // Version, locktime, counts, segwit marker and flag
+        assert_eq!(tx.weight().to_wu(), 396 + 42);
+
+        let tx = Transaction { version: 2, lock_time: LockTime::ZERO, input: vec![txin.clone(), txin], output: vec![txout.clone(), txout] };
+        assert_eq!(pairs_vsize(&tx), 198.0);
+        assert_eq!(tx.weight().to_wu(), 2 * 396 + 42);
+    }
+}